@@ -0,0 +1,954 @@
+use crate::emulator::CLOCK_SPEED;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const NR10: u16 = 0xFF10;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+/// Bits of 0xFF10-0xFF2F that always read back as 1, write-only and unused bits included.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+const DUTY_CYCLES: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Clone)]
+struct Length {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length { enabled: false, counter: 0, max }
+    }
+    fn load(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+    /// True when the counter runs out and the channel turns off.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+    /// Handles the length enable bit and trigger of an NRx4 write. Enabling the counter or
+    /// triggering in the half of the frame sequencer period that does not clock length clocks
+    /// it once more. True when the counter runs out without a trigger to restart the channel.
+    fn write(&mut self, value: u8, clocks_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = value & 0x40 != 0;
+        let trigger = value & 0x80 != 0;
+        let mut expired = false;
+        if !was_enabled && self.enabled && !clocks_next && self.counter > 0 {
+            self.counter -= 1;
+            expired = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && !clocks_next {
+                self.counter -= 1;
+            }
+        }
+        expired
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.counter = state.u16()?.min(self.max);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Envelope {
+    initial: u8,
+    up: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope { initial: 0, up: false, period: 0, volume: 0, timer: 0 }
+    }
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.up = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+    /// The DAC is off when the register would only ever produce silence.
+    fn dac(&self) -> bool {
+        self.initial != 0 || self.up
+    }
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.up && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.up && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.initial);
+        state.bool(self.up);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.initial = state.u8()?;
+        self.up = state.bool()?;
+        self.period = state.u8()?;
+        self.volume = state.u8()?;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+/// Frequency sweep of the first pulse channel.
+#[derive(Clone)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    /// A negating calculation since the trigger, after which leaving negate mode stops the channel.
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep { period: 0, negate: false, shift: 0, timer: 0, enabled: false, shadow: 0, negated: false }
+    }
+    /// The next frequency, above 2047 the channel turns off.
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+    fn reload(&mut self) {
+        self.timer = match self.period {
+            0 => 8,
+            period => period,
+        };
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow);
+        state.bool(self.negated);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.u8()?;
+        self.negate = state.bool()?;
+        self.shift = state.u8()?;
+        self.timer = state.u8()?;
+        self.enabled = state.bool()?;
+        self.shadow = state.u16()?;
+        self.negated = state.bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    position: u8,
+    frequency: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn new(sweep: bool) -> Pulse {
+        Pulse {
+            enabled: false,
+            duty: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: sweep.then(Sweep::new),
+        }
+    }
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 7;
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> u8 {
+        match self.enabled && DUTY_CYCLES[self.duty as usize] >> self.position & 1 != 0 {
+            true => self.envelope.volume,
+            false => 0,
+        }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.reload();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negated = false;
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.calculate();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked again right away, without being applied
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.duty);
+        state.u8(self.position);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(state);
+        }
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.duty = state.u8()? & 0x03;
+        self.position = state.u8()? & 0x07;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    frequency: u16,
+    timer: u32,
+    /// Cycles since the channel last read wave RAM
+    since_read: u32,
+    position: u8,
+    sample: u8,
+    volume: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac: false,
+            frequency: 0,
+            timer: 0,
+            since_read: u32::MAX,
+            position: 0,
+            sample: 0,
+            volume: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 31;
+            self.sample = self.ram[self.position as usize / 2] >> (4 * (1 - self.position % 2)) & 0x0F;
+            self.since_read = 0;
+        }
+        self.timer -= cycles;
+        self.since_read = self.since_read.saturating_add(cycles);
+    }
+    /// On the DMG the CPU only reaches wave RAM while the channel plays in the cycle the channel
+    /// reads it itself, and then gets the byte being read.
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if !self.enabled {
+            return Some((address - WAVE_RAM) as usize);
+        }
+        (self.since_read < 4).then_some(self.position as usize / 2)
+    }
+    fn output(&self) -> u8 {
+        match (self.enabled, self.volume) {
+            (false, _) | (_, 0) => 0,
+            (true, volume) => self.sample >> (volume - 1),
+        }
+    }
+    fn trigger(&mut self) {
+        // Triggering just as the channel reads wave RAM garbles the first bytes on the DMG
+        if self.enabled && self.timer <= 2 {
+            let index = ((self.position as usize + 1) & 31) / 2;
+            if index < 4 {
+                self.ram[0] = self.ram[index];
+            } else {
+                let block = index & !3;
+                self.ram.copy_within(block..block + 4, 0);
+            }
+        }
+        self.enabled = self.dac;
+        self.position = 0;
+        self.since_read = u32::MAX;
+        // The first sample is read a few cycles later than a full period
+        self.timer = self.period() + 6;
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        state.u32(self.since_read);
+        state.u8(self.position);
+        state.u8(self.sample);
+        state.u8(self.volume);
+        self.length.save_state(state);
+        state.bytes(&self.ram);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.dac = state.bool()?;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u32()?;
+        self.since_read = state.u32()?;
+        self.position = state.u8()? & 31;
+        self.sample = state.u8()? & 0x0F;
+        self.volume = state.u8()? & 0x03;
+        self.length.load_state(state)?;
+        state.bytes_into(&mut self.ram)
+    }
+}
+
+#[derive(Clone)]
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    narrow: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+    fn tick(&mut self, mut cycles: u32) {
+        // Shifts of 14 and 15 leave the LFSR without a clock
+        if self.shift >= 14 {
+            return;
+        }
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.narrow {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> u8 {
+        match self.enabled && self.lfsr & 1 == 0 {
+            true => self.envelope.volume,
+            false => 0,
+        }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.shift);
+        state.bool(self.narrow);
+        state.u8(self.divisor);
+        state.u16(self.lfsr);
+        state.u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.shift = state.u8()? & 0x0F;
+        self.narrow = state.bool()?;
+        self.divisor = state.u8()? & 0x07;
+        self.lfsr = state.u16()? & 0x7FFF;
+        self.timer = state.u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+/// The four sound channels, the frame sequencer and the mixer behind 0xFF10-0xFF3F.
+#[derive(Clone)]
+pub struct Apu {
+    power: bool,
+    /// Last values written to 0xFF10-0xFF2F, for reading back.
+    registers: [u8; 0x20],
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    /// The frame sequencer step to run next, clocked at 512 Hz from DIV.
+    step: u8,
+    sample_rate: Option<u32>,
+    sample_cycles: u64,
+    /// Mixer output summed over the cycles since the last sample, for averaging down to the sample rate.
+    levels: [f32; 2],
+    level_cycles: u32,
+    samples: Vec<f32>,
+    capacitors: [f32; 2],
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    /// In the state the boot ROM leaves it, after the startup chime has faded out.
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            power: true,
+            registers: [0; 0x20],
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            step: 0,
+            sample_rate: None,
+            sample_cycles: 0,
+            levels: [0.0; 2],
+            level_cycles: 0,
+            samples: vec![],
+            capacitors: [0.0; 2],
+        };
+        for (address, value) in [(0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF16, 0x3F), (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF20, 0xFF), (0xFF24, 0x77), (0xFF25, 0xF3)] {
+            apu.write(address, value);
+        }
+        apu.pulse1.enabled = true;
+        apu
+    }
+
+    /// Collects stereo samples at this rate for `take_samples`, `None` skips mixing altogether.
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.sample_rate = sample_rate.filter(|rate| *rate > 0);
+        self.sample_cycles = 0;
+        self.levels = [0.0; 2];
+        self.level_cycles = 0;
+        self.samples.clear();
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    /// Interleaved left and right samples between -1 and 1 mixed since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// The frame sequencer alternates between steps that clock the length counters and ones that do not.
+    fn clocks_length_next(&self) -> bool {
+        self.step.is_multiple_of(2)
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52 => {
+                let channels = [self.pulse1.enabled, self.pulse2.enabled, self.wave.enabled, self.noise.enabled];
+                let status = channels.iter().rev().fold(0, |status, enabled| status << 1 | *enabled as u8);
+                (self.power as u8) << 7 | 0x70 | status
+            }
+            WAVE_RAM..=WAVE_RAM_END => match self.wave.ram_index(address) {
+                Some(index) => self.wave.ram[index],
+                None => 0xFF,
+            },
+            NR10..=0xFF2F => {
+                let index = (address - NR10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if let WAVE_RAM..=WAVE_RAM_END = address {
+            if let Some(index) = self.wave.ram_index(address) {
+                self.wave.ram[index] = value;
+            }
+            return;
+        }
+        if address == NR52 {
+            self.set_power(value & 0x80 != 0);
+            return;
+        }
+        if !(NR10..NR52).contains(&address) {
+            return;
+        }
+        if !self.power {
+            // Only the length counters keep working without power on the DMG
+            match address {
+                0xFF11 => self.pulse1.length.load(value as u16 & 0x3F),
+                0xFF16 => self.pulse2.length.load(value as u16 & 0x3F),
+                0xFF1B => self.wave.length.load(value as u16),
+                0xFF20 => self.noise.length.load(value as u16 & 0x3F),
+                _ => {}
+            }
+            return;
+        }
+        self.registers[(address - NR10) as usize] = value;
+        let clocks_next = self.clocks_length_next();
+        match address {
+            0xFF10 => {
+                let sweep = self.pulse1.sweep.as_mut().unwrap();
+                sweep.period = (value >> 4) & 0x07;
+                sweep.negate = value & 0x08 != 0;
+                sweep.shift = value & 0x07;
+                if !sweep.negate && sweep.negated {
+                    self.pulse1.enabled = false;
+                }
+            }
+            0xFF11 | 0xFF16 => {
+                let pulse = self.pulse(address);
+                pulse.duty = value >> 6;
+                pulse.length.load(value as u16 & 0x3F);
+            }
+            0xFF12 | 0xFF17 => {
+                let pulse = self.pulse(address);
+                pulse.envelope.write(value);
+                if !pulse.envelope.dac() {
+                    pulse.enabled = false;
+                }
+            }
+            0xFF13 | 0xFF18 => {
+                let pulse = self.pulse(address);
+                pulse.frequency = (pulse.frequency & 0x700) | value as u16;
+            }
+            0xFF14 | 0xFF19 => {
+                let pulse = self.pulse(address);
+                pulse.frequency = (pulse.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if pulse.length.write(value, clocks_next) {
+                    pulse.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    pulse.trigger();
+                }
+            }
+            0xFF1A => {
+                self.wave.dac = value & 0x80 != 0;
+                if !self.wave.dac {
+                    self.wave.enabled = false;
+                }
+            }
+            0xFF1B => self.wave.length.load(value as u16),
+            0xFF1C => self.wave.volume = (value >> 5) & 0x03,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if self.wave.length.write(value, clocks_next) {
+                    self.wave.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            0xFF20 => self.noise.length.load(value as u16 & 0x3F),
+            0xFF21 => {
+                self.noise.envelope.write(value);
+                if !self.noise.envelope.dac() {
+                    self.noise.enabled = false;
+                }
+            }
+            0xFF22 => {
+                self.noise.shift = value >> 4;
+                self.noise.narrow = value & 0x08 != 0;
+                self.noise.divisor = value & 0x07;
+            }
+            0xFF23 => {
+                if self.noise.length.write(value, clocks_next) {
+                    self.noise.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pulse(&mut self, address: u16) -> &mut Pulse {
+        match address {
+            0xFF10..=0xFF14 => &mut self.pulse1,
+            _ => &mut self.pulse2,
+        }
+    }
+
+    /// Turning the APU off clears every register but the length counters and wave RAM.
+    fn set_power(&mut self, power: bool) {
+        if power == self.power {
+            return;
+        }
+        if !power {
+            let lengths = [self.pulse1.length.counter, self.pulse2.length.counter, self.wave.length.counter, self.noise.length.counter];
+            for address in NR10..NR52 {
+                self.write(address, 0);
+            }
+            self.pulse1.length.counter = lengths[0];
+            self.pulse2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+        } else {
+            self.step = 0;
+            self.pulse1.position = 0;
+            self.pulse2.position = 0;
+            self.wave.sample = 0;
+        }
+        self.power = power;
+    }
+
+    /// Runs the channels for a number of T-cycles and mixes the samples that fall in them.
+    pub fn tick(&mut self, cycles: usize) {
+        if self.power {
+            let cycles = cycles as u32;
+            self.pulse1.tick(cycles);
+            self.pulse2.tick(cycles);
+            self.wave.tick(cycles);
+            self.noise.tick(cycles);
+        }
+
+        let Some(rate) = self.sample_rate else {
+            return;
+        };
+        let levels = self.mix();
+        for (level, mixed) in self.levels.iter_mut().zip(levels) {
+            *level += mixed * cycles as f32;
+        }
+        self.level_cycles += cycles as u32;
+        self.sample_cycles += cycles as u64 * rate as u64;
+        if self.sample_cycles < CLOCK_SPEED as u64 {
+            return;
+        }
+        // Averaging the mixer output since the previous sample filters out what is too high to
+        // be represented at the sample rate
+        let average = self.levels.map(|level| level / self.level_cycles.max(1) as f32);
+        self.levels = [0.0; 2];
+        self.level_cycles = 0;
+        while self.sample_cycles >= CLOCK_SPEED as u64 {
+            self.sample_cycles -= CLOCK_SPEED as u64;
+            self.push_sample(average, rate);
+        }
+    }
+
+    /// A falling edge of DIV bit 4, the 512 Hz clock of the frame sequencer.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        if self.step.is_multiple_of(2) {
+            if self.pulse1.length.clock() {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.clock() {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.step == 2 || self.step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if self.step == 7 {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.step = (self.step + 1) & 7;
+    }
+
+    /// Left and right output of the mixer right now.
+    fn mix(&self) -> [f32; 2] {
+        let dacs = [self.pulse1.envelope.dac(), self.pulse2.envelope.dac(), self.wave.dac, self.noise.envelope.dac()];
+        let outputs = [self.pulse1.output(), self.pulse2.output(), self.wave.output(), self.noise.output()];
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        std::array::from_fn(|side| {
+            // Left is the high nibble of NR51 and NR50, right the low one
+            let shift = 4 * (1 - side);
+            let mut sum = 0.0;
+            for channel in 0..4 {
+                if self.power && dacs[channel] && (nr51 >> (shift + channel)) & 1 != 0 {
+                    sum += outputs[channel] as f32 / 7.5 - 1.0;
+                }
+            }
+            let volume = ((nr50 >> shift) & 0x07) as f32 + 1.0;
+            sum / 4.0 * volume / 8.0
+        })
+    }
+
+    fn push_sample(&mut self, input: [f32; 2], rate: u32) {
+        // The output capacitor slowly pulls the signal back to 0, which removes the DC offset of the DACs
+        let charge = 0.999958f32.powf(CLOCK_SPEED as f32 / rate as f32);
+        for (capacitor, input) in self.capacitors.iter_mut().zip(input) {
+            let output = input - *capacitor;
+            *capacitor = input - output * charge;
+            self.samples.push(output);
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.power);
+        state.bytes(&self.registers);
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.u8(self.step);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.power = state.bool()?;
+        state.bytes_into(&mut self.registers)?;
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.step = state.u8()? & 0x07;
+        Ok(())
+    }
+
+    /// Wave RAM as stored, whether or not the CPU could read it right now.
+    pub fn wave_ram(&self) -> &[u8; 16] {
+        &self.wave.ram
+    }
+
+    pub fn set_wave_ram_byte(&mut self, address: u16, value: u8) {
+        self.wave.ram[(address - WAVE_RAM) as usize] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::{Apu, NR52};
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52, 0x00);
+        apu.write(NR52, 0x80);
+        apu
+    }
+
+    #[test]
+    fn read_masks() {
+        let mut apu = powered();
+        for address in 0xFF10..=0xFF2F {
+            if address != NR52 {
+                apu.write(address, 0x00);
+            }
+        }
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+            0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        for (address, expected) in (0xFF10..=0xFF2F).zip(expected) {
+            assert_eq!(apu.read(address), expected, "{:#06x}", address);
+        }
+
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(NR52), 0xF1);
+        assert_eq!(apu.read(0xFF14), 0xFF);
+
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        apu.write(0xFF12, 0xF0);
+        assert_eq!(apu.read(0xFF12), 0x00);
+    }
+
+    #[test]
+    fn length_counter() {
+        let mut apu = powered();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3E);
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(NR52) & 1, 1);
+        // Two steps clock length, the second one runs the counter out
+        for _ in 0..2 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.read(NR52) & 1, 1);
+        for _ in 0..2 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.read(NR52) & 1, 0);
+
+        // Lengths can be written while the APU is off
+        apu.write(NR52, 0x00);
+        apu.write(0xFF20, 0x3F);
+        apu.write(NR52, 0x80);
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF23, 0xC0);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read(NR52) & 8, 0);
+    }
+
+    #[test]
+    fn sweep_overflow() {
+        let mut apu = powered();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF13, 0xFF);
+        apu.write(0xFF14, 0x87);
+        assert_eq!(apu.read(NR52) & 1, 0);
+
+        apu.write(0xFF10, 0x19);
+        apu.write(0xFF14, 0x87);
+        assert_eq!(apu.read(NR52) & 1, 1);
+        apu.write(0xFF10, 0x11);
+        assert_eq!(apu.read(NR52) & 1, 0);
+    }
+
+    #[test]
+    fn mixing() {
+        let mut apu = powered();
+        apu.set_sample_rate(Some(48000));
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF14, 0x87);
+        apu.tick(4194304 / 64);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 2 * 750);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.1));
+        assert!(samples.iter().all(|sample| sample.abs() <= 1.0));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    fn wave_playing(apu: &mut Apu) {
+        for (offset, value) in (0..16).zip(0x10..) {
+            apu.write(0xFF30 + offset, value);
+        }
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1D, 0x00);
+        apu.write(0xFF1E, 0x87);
+        assert_eq!(apu.read(NR52) & 4, 4);
+    }
+
+    #[test]
+    fn wave_ram_after_trigger() {
+        let mut apu = powered();
+        wave_playing(&mut apu);
+        assert_eq!(apu.read(0xFF30), 0xFF);
+        apu.write(0xFF30, 0x00);
+        apu.tick(0x100 * 2 + 6);
+        assert_eq!(apu.read(0xFF30), 0x10);
+    }
+
+    #[test]
+    fn wave_ram_after_lowering_frequency() {
+        let mut apu = powered();
+        wave_playing(&mut apu);
+        // The pending timer now outlasts the shorter period
+        apu.write(0xFF1D, 0xFF);
+        apu.write(0xFF1E, 0x07);
+        assert_eq!(apu.read(0xFF30), 0xFF);
+        apu.write(0xFF30, 0x00);
+        assert_eq!(apu.read(NR52) & 4, 4);
+    }
+}
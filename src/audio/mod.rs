@@ -0,0 +1,16 @@
+pub mod null;
+pub mod wav;
+
+use std::io;
+
+/// Receives the sound of the emulator, the counterpart of `Output` for audio.
+pub trait AudioSink {
+    /// Stereo samples per second this sink wants, the APU output is resampled to it.
+    fn sample_rate(&self) -> u32;
+    /// Interleaved left and right samples between -1 and 1.
+    fn write_samples(&mut self, samples: &[f32]);
+    /// Called once no more samples will come.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
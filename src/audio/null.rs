@@ -0,0 +1,19 @@
+use crate::audio::AudioSink;
+
+/// Throws the samples away, for runs that only need the APU to be emulated.
+pub struct Null {
+    sample_rate: u32,
+}
+
+impl AudioSink for Null {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn write_samples(&mut self, _: &[f32]) {}
+}
+
+impl Null {
+    pub fn new(sample_rate: u32) -> Self {
+        Null { sample_rate }
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::audio::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+/// The RIFF size field has to fit the data and the rest of the header in 32 bits, rounded down to
+/// whole stereo frames.
+const MAX_DATA_BYTES: u32 = (u32::MAX - (HEADER_SIZE - 8)) & !3;
+
+/// Records 16-bit stereo PCM to a WAV file.
+pub struct Wav<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    data_bytes: u32,
+    error: Option<io::Error>,
+}
+
+impl Wav<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> io::Result<Self> {
+        Wav::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> Wav<W> {
+    /// Writes a header for an empty recording, the sizes in it are filled in by `finish`.
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        writer.write_all(&header(sample_rate, 0))?;
+        Ok(Wav { writer, sample_rate, data_bytes: 0, error: None })
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.finish()?;
+        Ok(self.writer)
+    }
+}
+
+fn header(sample_rate: u32, data_bytes: u32) -> [u8; HEADER_SIZE as usize] {
+    let channels = 2u16;
+    let bits = 16u16;
+    let block_align = channels * bits / 8;
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(HEADER_SIZE - 8 + data_bytes).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&channels.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&bits.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_bytes.to_le_bytes());
+    header
+}
+
+impl<W: Write + Seek> AudioSink for Wav<W> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            data.extend_from_slice(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        let room = (MAX_DATA_BYTES - self.data_bytes) as usize;
+        let full = data.len() > room;
+        data.truncate(room);
+        // Errors are kept for `finish`, there is no good way to report them from the middle of a frame
+        match self.writer.write_all(&data) {
+            Ok(()) => self.data_bytes += data.len() as u32,
+            Err(error) => {
+                self.error = Some(error);
+                return;
+            }
+        }
+        if full {
+            self.error = Some(io::Error::other("the recording reached the 4 GiB WAV size limit"));
+        }
+    }
+
+    /// Fills in the header sizes, then reports any error from writing the samples.
+    fn finish(&mut self) -> io::Result<()> {
+        let error = self.error.take();
+        let position = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header(self.sample_rate, self.data_bytes))?;
+        self.writer.seek(SeekFrom::Start(position))?;
+        self.writer.flush()?;
+        error.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::audio::AudioSink;
+    use crate::audio::wav::{Wav, MAX_DATA_BYTES};
+
+    #[test]
+    fn header() {
+        let mut wav = Wav::new(Cursor::new(vec![]), 44100).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 0.5]);
+        let data = wav.into_inner().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x3F]);
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut wav = Wav::new(Cursor::new(vec![]), 44100).unwrap();
+        // Pretend almost 4 GiB were already recorded
+        wav.data_bytes = MAX_DATA_BYTES - 4;
+        wav.write_samples(&[0.0, 0.0, 0.0, 0.0]);
+        wav.write_samples(&[0.0, 0.0]);
+        assert!(wav.finish().is_err());
+        let data = wav.into_inner().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 4);
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), u32::MAX - 3);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), MAX_DATA_BYTES);
+    }
+}
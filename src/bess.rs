@@ -0,0 +1,236 @@
+use crate::bus::{Bus, ROM_N_SIZE};
+use crate::cartridge::Header;
+use crate::cpu::Cpu;
+use crate::ppu::Ppu;
+use crate::state::StateError;
+
+pub const BESS_MAGIC: &[u8; 4] = b"BESS";
+const CORE_MAJOR: u16 = 1;
+const CORE_MINOR: u16 = 1;
+const CORE_SIZE: usize = 0xD0;
+/// DMG family, model and revision left unspecified.
+const DMG_MODEL: &[u8; 4] = b"GD  ";
+const DMG_WRAM_SIZE: usize = 0x2000;
+const DMG_VRAM_SIZE: usize = 0x2000;
+/// CGB revision C, the most common one.
+const CGB_MODEL: &[u8; 4] = b"CC  ";
+
+/// Whether a file ends in a BESS footer, it may start with the native state of another emulator.
+pub fn is_bess(data: &[u8]) -> bool {
+    data.ends_with(BESS_MAGIC)
+}
+
+fn block(file: &mut Vec<u8>, ident: &[u8; 4], data: &[u8]) {
+    file.extend_from_slice(ident);
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(data);
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+/// Title and global checksum bytes exactly as they are in the ROM header, the INFO block format.
+fn info(rom: &[u8]) -> [u8; 0x12] {
+    let mut info = [0; 0x12];
+    info[..0x10].copy_from_slice(&rom[0x0134..0x0144]);
+    info[0x10..].copy_from_slice(&rom[0x014E..0x0150]);
+    info
+}
+
+fn describe_info(info: &[u8]) -> String {
+    let title: String = info[..0x10].iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect();
+    format!("{} ({:02X}{:02X})", title.trim_end(), info[0x10], info[0x11])
+}
+
+/// Writes the machine as a file of raw memory areas followed by NAME, INFO, CORE, MBC and RTC blocks.
+pub fn export(cpu: &Cpu, bus: &mut Bus, header: &Header) -> Vec<u8> {
+    let ram_bytes = header.ram_bytes().unwrap_or(0).min(bus.memory.eram.len());
+    let memory = &bus.memory;
+    // WRAM, VRAM, cartridge RAM, OAM, HRAM and the two CGB palette areas, which a DMG does not have
+    let areas: [&[u8]; 7] = match bus.cgb {
+        true => [&memory.wram, &memory.vram, &memory.eram[..ram_bytes], &memory.oam, &memory.hram, bus.bg_palettes.data(), bus.obj_palettes.data()],
+        false => [&memory.wram[..DMG_WRAM_SIZE], &memory.vram[..DMG_VRAM_SIZE], &memory.eram[..ram_bytes], &memory.oam, &memory.hram, &[], &[]],
+    };
+    let mut file = vec![];
+    let mut pointers = vec![];
+    for area in areas {
+        pointers.push((area.len(), if area.is_empty() { 0 } else { file.len() }));
+        file.extend_from_slice(area);
+    }
+    let first_block = file.len();
+
+    block(&mut file, b"NAME", format!("rusty-gb v{}", env!("CARGO_PKG_VERSION")).as_bytes());
+    block(&mut file, b"INFO", &info(&memory.rom));
+
+    let mut core = Vec::with_capacity(CORE_SIZE);
+    core.extend_from_slice(&CORE_MAJOR.to_le_bytes());
+    core.extend_from_slice(&CORE_MINOR.to_le_bytes());
+    core.extend_from_slice(if bus.cgb { CGB_MODEL } else { DMG_MODEL });
+    for register in cpu.registers() {
+        core.extend_from_slice(&register.to_le_bytes());
+    }
+    core.push(cpu.get_ime() as u8);
+    core.push(bus.get(0xFFFF));
+    core.push(if cpu.stopped() { 2 } else { cpu.halted() as u8 });
+    core.push(0);
+    core.extend_from_slice(&bus.io_registers());
+    for (size, offset) in pointers {
+        core.extend_from_slice(&(size as u32).to_le_bytes());
+        core.extend_from_slice(&(offset as u32).to_le_bytes());
+    }
+    block(&mut file, b"CORE", &core);
+
+    let registers = bus.mbc_registers();
+    if !registers.is_empty() {
+        let mut mbc = vec![];
+        for (address, value) in registers {
+            mbc.extend_from_slice(&address.to_le_bytes());
+            mbc.push(value);
+        }
+        block(&mut file, b"MBC ", &mbc);
+    }
+    if let Some(rtc) = bus.rtc() {
+        block(&mut file, b"RTC ", &rtc.to_footer());
+    }
+    block(&mut file, b"END ", &[]);
+
+    file.extend_from_slice(&(first_block as u32).to_le_bytes());
+    file.extend_from_slice(BESS_MAGIC);
+    file
+}
+
+/// Restores the blocks this emulator understands and skips the rest. The pixel pipeline is not
+/// part of the format, so the PPU starts the current line over.
+pub fn import(data: &[u8], cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu) -> Result<(), StateError> {
+    if data.len() < 8 || !is_bess(data) {
+        return Err(StateError::NotAState);
+    }
+    let mut position = u32_at(data, data.len() - 8);
+    let end = data.len() - 8;
+    let mut core = None;
+    let mut mbc = None;
+    let mut rtc = None;
+    loop {
+        if position + 8 > end {
+            return Err(StateError::Corrupt);
+        }
+        let ident = &data[position..position + 4];
+        let len = u32_at(data, position + 4);
+        let block = data.get(position + 8..position + 8 + len).ok_or(StateError::Corrupt)?;
+        position += 8 + len;
+        match ident {
+            b"END " => break,
+            b"INFO" => {
+                if block.len() < 0x12 {
+                    return Err(StateError::Corrupt);
+                }
+                let expected = info(&bus.memory.rom);
+                if block[..0x12] != expected {
+                    return Err(StateError::RomMismatch { expected: describe_info(&expected), found: describe_info(block) });
+                }
+            }
+            b"CORE" => core = Some(block),
+            b"MBC " => mbc = Some(block),
+            b"RTC " => rtc = Some(block),
+            _ => {}
+        }
+    }
+
+    let core = core.ok_or(StateError::Corrupt)?;
+    if core.len() < CORE_SIZE {
+        return Err(StateError::Corrupt);
+    }
+    let major = u16_at(core, 0);
+    if major != CORE_MAJOR {
+        return Err(StateError::IncompatibleVersion { found: major as u32, expected: CORE_MAJOR as u32 });
+    }
+    // The SGB runs the same CPU and PPU as the DMG, everything past the CORE block is optional.
+    // CGB states only load into a machine in CGB mode, and the other way round.
+    let cgb = match core[4] {
+        b'G' | b'S' => false,
+        b'C' => true,
+        _ => return Err(StateError::UnsupportedModel(String::from_utf8_lossy(&core[4..8]).into_owned())),
+    };
+    if cgb != bus.cgb {
+        return Err(StateError::UnsupportedModel(String::from_utf8_lossy(&core[4..8]).into_owned()));
+    }
+    let mut areas = [&[][..]; 7];
+    for (i, area) in areas.iter_mut().enumerate() {
+        let size = u32_at(core, 0x98 + i * 8);
+        let offset = u32_at(core, 0x9C + i * 8);
+        *area = data.get(offset..offset + size).ok_or(StateError::Corrupt)?;
+    }
+    let mbc_writes = match mbc {
+        Some(block) => {
+            let writes: Vec<(u16, u8)> = block.chunks_exact(3).map(|write| (u16_at(write, 0), write[2])).collect();
+            if block.len() % 3 != 0 || writes.iter().any(|(address, _)| *address >= 2 * ROM_N_SIZE) {
+                return Err(StateError::Corrupt);
+            }
+            writes
+        }
+        None => vec![],
+    };
+
+    cpu.set_registers(std::array::from_fn(|i| u16_at(core, 0x08 + i * 2)));
+    cpu.set_ime(core[0x14] != 0);
+    cpu.set_halted(core[0x16] == 1);
+    cpu.set_stopped(core[0x16] == 2);
+    bus.set_io_registers(&core[0x18..0x98]);
+    bus.set(0xFFFF, core[0x15]);
+
+    let memory = &mut bus.memory;
+    for (target, area) in [&mut memory.wram, &mut memory.vram, &mut memory.eram, &mut memory.oam, &mut memory.hram].into_iter().zip(&areas[..5]) {
+        let len = area.len().min(target.len());
+        target[..len].copy_from_slice(&area[..len]);
+    }
+    for (address, value) in mbc_writes {
+        bus.set(address, value);
+    }
+    if cgb {
+        bus.bg_palettes.set_data(areas[5]);
+        bus.obj_palettes.set_data(areas[6]);
+    }
+    if let (Some(block), Some(clock)) = (rtc, bus.rtc()) {
+        clock.load_footer(block);
+    }
+    ppu.restart_line(bus);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::bess::is_bess;
+    use crate::emulator::Emulator;
+    use crate::input;
+    use crate::model::Model;
+    use crate::output::dummy::Dummy;
+
+    #[test]
+    fn blocks() {
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("01-special.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        // The cartridge supports a CGB, which would save twice the video and work RAM
+        emu.set_model(Model::Dmg);
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        let data = emu.export_bess();
+        assert!(is_bess(&data));
+        let first_block = u32::from_le_bytes(data[data.len() - 8..data.len() - 4].try_into().unwrap()) as usize;
+        assert_eq!(first_block, 0x2000 + 0x2000 + 0xA0 + 0x7F);
+        assert_eq!(&data[first_block..first_block + 4], b"NAME");
+
+        let mut other = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("01-special.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        other.set_model(Model::Dmg);
+        other.import_bess(&data).unwrap();
+        assert_eq!(other.export_bess(), data);
+        for address in [0xC000, 0xC123, 0xDFFF, 0xFF40, 0xFF44, 0xFF47, 0xFF80] {
+            assert_eq!(other.read_memory(address), emu.read_memory(address));
+        }
+    }
+}
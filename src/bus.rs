@@ -1,14 +1,23 @@
 #![allow(dead_code)]
 
 use std::cmp::PartialEq;
+use std::sync::Arc;
 use bitfield::{Bit, BitMut};
 use rand::{random, Rng};
-use crate::input::Input;
-use crate::mbc::{MBC, MBC0, MBC1, MBC2, MBC3};
+use crate::apu::{Apu, NR52, WAVE_RAM, WAVE_RAM_END};
+use crate::input::Button;
+use crate::mbc::{MBC, MBC0, MBC1, MBC2, MBC3, MBC5};
+use crate::cartridge::Header;
+use crate::error::LoadError;
+use crate::hdma::{Hdma, HDMA_BLOCK, HDMA_BLOCK_CYCLES};
 use crate::memory::Memory;
+use crate::palette::ColorPalettes;
+use crate::rtc::Rtc;
+use crate::sgb::Sgb;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::output::Output;
 use crate::ppu::PpuState;
-use crate::ppu::PpuState::{OAMFetch, PixelTransfer};
+use crate::ppu::PpuState::{HBlank, OAMFetch, PixelTransfer};
 
 pub const ROM_0: u16 = 0x0000;
 pub const ROM_0_END: u16 = 0x3FFF;
@@ -43,6 +52,7 @@ pub const IO_REGISTERS_SIZE: u16 = IO_REGISTERS_END - IO_REGISTERS + 1;
 pub const HRAM_SIZE: u16 = HRAM_END - HRAM + 1;
 pub const INT_ENABLE_SIZE: u16 = INT_ENABLE_END - INT_ENABLE + 1;
 
+#[derive(Clone)]
 pub struct MMAPRegisters {
     pub sb: u8,
     pub sc: u8,
@@ -59,20 +69,39 @@ pub struct MMAPRegisters {
     lcdc: u8,
     pub(crate) lcds: u8,
     bg_palette_data: u8,
-    cgb_bg_color_autoincrement: bool,
-    cgb_bg_color_address: u8,
     obj_palette_0: u8,
     obj_palette_1: u8,
     interrupt_enable: u8,
     interrupt_flag: u8,
 }
+#[derive(Clone)]
 pub struct Bus {
-    memory: Memory,
+    pub(crate) memory: Memory,
     pub(crate) registers: MMAPRegisters,
+    pub(crate) apu: Apu,
     mbc: Box<dyn MBC>,
     pub ppu_state: PpuState,
     pub fifo: Vec<u8>,
     pub dma_address: u16,
+    /// Buttons held by each player, up to four on a Super Game Boy.
+    joypad_buttons: [u8; 4],
+    boot_rom: Option<Arc<[u8]>>,
+    boot_rom_mapped: bool,
+    /// Game Boy Color mode, with its registers, banks and palettes.
+    pub(crate) cgb: bool,
+    pub(crate) bg_palettes: ColorPalettes,
+    pub(crate) obj_palettes: ColorPalettes,
+    /// OPRI bit 0, sprites are ordered by X coordinate like on the DMG when set.
+    obj_priority_by_x: bool,
+    hdma: Hdma,
+    /// M-cycles VRAM DMA holds the CPU for, the emulator takes them after each step.
+    hdma_cycles: usize,
+    /// KEY1, the CPU and timers run at twice the speed of the PPU and APU in double speed.
+    double_speed: bool,
+    speed_switch_armed: bool,
+    div_reset: bool,
+    /// The Super Game Boy, which listens to P1 for command packets.
+    pub(crate) sgb: Option<Box<Sgb>>,
 }
 
 impl Bus {
@@ -95,24 +124,37 @@ impl Bus {
                 lcdc: 0,
                 lcds: 0,
                 bg_palette_data: 0,
-                cgb_bg_color_autoincrement: false,
-                cgb_bg_color_address: 0,
                 obj_palette_0: 0,
                 obj_palette_1: 0,
                 interrupt_enable: 0,
                 interrupt_flag: 0,
             },
+            apu: Apu::new(),
             mbc: Box::new(MBC0::new()),
             ppu_state: OAMFetch,
             fifo: vec![],
-            dma_address: 0
+            dma_address: 0,
+            joypad_buttons: [0; 4],
+            boot_rom: None,
+            boot_rom_mapped: false,
+            cgb: false,
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            obj_priority_by_x: false,
+            hdma: Hdma::new(),
+            hdma_cycles: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            div_reset: false,
+            sgb: None,
         }
     }
     pub fn get(&self, address: u16) -> u8 {
         match address {
+            ..=0x08FF if self.boot_rom_covers(address) => self.boot_rom.as_ref().unwrap()[address as usize],
             ..=0x7FFF | 0xA000..=0xBFFF => { self.mbc.read(address, &self.memory) },
             0xe000..=0xfdff | 0xfea0..=0xfeff => 0xFF,
-            0xFF00 => self.registers.joypad,
+            0xFF00 => self.get_joypad(),
             0xFF01 => self.registers.sb,
             0xFF02 => self.registers.sc,
             0xFF04 => self.registers.div,
@@ -129,16 +171,19 @@ impl Bus {
             0xFF49 => self.registers.obj_palette_1,
             0xFF4A => self.registers.wy,
             0xFF4B => self.registers.wx,
-            0xFF4F => self.memory.current_vram as u8,
+            0xFF4F if self.cgb => 0xFE | self.memory.current_vram as u8,
+            0xFF68 if self.cgb => self.bg_palettes.read_spec(),
+            0xFF69 if self.cgb => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb => self.obj_palettes.read_spec(),
+            0xFF6B if self.cgb => self.obj_palettes.read_data(),
+            0xFF6C if self.cgb => 0xFE | self.obj_priority_by_x as u8,
+            0xFF70 if self.cgb => 0xF8 | self.memory.current_wram as u8,
+            0xFF55 if self.cgb => self.hdma.status(),
+            0xFF4D if self.cgb => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 => 0xFF,
+            0xFF10..=0xFF3F => self.apu.read(address),
             0xFF0F => self.registers.interrupt_flag,
             0xFFFF => self.registers.interrupt_enable,
-            0xFF00 => {
-                if self.get_joypad_dpad_buttons() && self.get_joypad_select_buttons() {
-                    0xFF
-                } else {
-                    self.memory.get(address)
-                }
-            },
             _ => self.memory.get(address)
         }
     }
@@ -182,7 +227,12 @@ impl Bus {
             0xFF41 => {
                 self.registers.lcds = (value & 0b11111000) | (self.memory.get(address) & 0b111);
             },
-            0xFF00 => self.registers.joypad = value,
+            0xFF00 => {
+                self.registers.joypad = value & 0b00110000;
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                }
+            }
             0xFF01 => self.registers.sb = value,
             0xFF02 => self.registers.sc = value,
             0xFF04 => self.registers.div = value,
@@ -197,17 +247,31 @@ impl Bus {
             0xFF49 => self.registers.obj_palette_1 = value,
             0xFF4A => self.registers.wy = value,
             0xFF4B => self.registers.wx = value,
-            0xFF4F => self.memory.current_vram = (value & 1) as u16,
-            0xFF68 => {
-                self.registers.cgb_bg_color_autoincrement = (value >> 7) != 0;
-                self.registers.cgb_bg_color_address = value & 0b00111111;
-            },
-            0xFF70 => {
+            0xFF4F if self.cgb => self.memory.current_vram = (value & 1) as u16,
+            0xFF68 if self.cgb => self.bg_palettes.write_spec(value),
+            0xFF69 if self.cgb => self.bg_palettes.write_data(value),
+            0xFF6A if self.cgb => self.obj_palettes.write_spec(value),
+            0xFF6B if self.cgb => self.obj_palettes.write_data(value),
+            0xFF6C if self.cgb => self.obj_priority_by_x = value & 0x01 != 0,
+            0xFF70 if self.cgb => {
                 self.memory.current_wram = (value & 0b111) as u16;
                 if self.memory.current_wram == 0 {
                     self.memory.current_wram = 1;
                 }
             },
+            0xFF51..=0xFF54 if self.cgb => self.hdma.set_register(address, value),
+            0xFF55 if self.cgb => {
+                let blocks = self.hdma.start(value);
+                self.hdma_copy(blocks);
+                // Started during HBlank, the first block does not wait for the next one
+                if self.hdma.active() && matches!(self.ppu_state, HBlank) {
+                    self.hdma_copy(1);
+                }
+            },
+            0xFF4D if self.cgb => self.speed_switch_armed = value & 0x01 != 0,
+            // The DMG has none of these
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 => {},
+            0xFF10..=0xFF3F => self.apu.write(address, value),
             0xFF0F => self.registers.interrupt_flag = value,
             0xFFFF => self.registers.interrupt_enable = value,
             0xFFFF => self.registers.interrupt_enable = value,
@@ -216,6 +280,13 @@ impl Bus {
             0xFF46 => {
                 self.dma_address = value as u16 * 0x100;
             }
+            0xFF50 => {
+                // A non-zero write unmaps the boot ROM until the next power on
+                if value != 0 {
+                    self.boot_rom_mapped = false;
+                }
+                self.memory.set(address, value);
+            }
             _ => self.memory.set(address, value)
         }
     }
@@ -451,44 +522,311 @@ impl Bus {
     pub fn get_joypad_dpad_buttons(&self) -> bool {
         self.registers.joypad.bit(4)
     }
-    pub fn set_joypad_set_start_down(&mut self) {
-        self.registers.joypad.set_bit(3, false)
+    pub fn press(&mut self, button: Button) {
+        self.press_player(0, button);
+    }
+    pub fn release(&mut self, button: Button) {
+        self.release_player(0, button);
+    }
+    /// Presses a button on one of the controllers a Super Game Boy reads after MLT_REQ, players
+    /// past the fourth are ignored. Without one only the first is read.
+    pub fn press_player(&mut self, player: usize, button: Button) {
+        let Some(buttons) = self.joypad_buttons.get_mut(player) else {
+            return;
+        };
+        let new = *buttons & button.mask() == 0;
+        *buttons |= button.mask();
+        // Only the controller P1 reads can pull a line low
+        if new && player == self.sgb.as_ref().map_or(0, |sgb| sgb.player()) {
+            self.set_int_request_joypad(true);
+        }
+    }
+    pub fn release_player(&mut self, player: usize, button: Button) {
+        if let Some(buttons) = self.joypad_buttons.get_mut(player) {
+            *buttons &= !button.mask();
+        }
+    }
+    /// Input lines pulled low by pressed buttons in the selected groups.
+    fn pressed_lines(&self) -> u8 {
+        let player = self.sgb.as_ref().map_or(0, |sgb| sgb.player());
+        let mut pressed = 0;
+        if !self.get_joypad_select_buttons() {
+            pressed |= self.joypad_buttons[player] & 0x0F;
+        }
+        if !self.get_joypad_dpad_buttons() {
+            pressed |= self.joypad_buttons[player] >> 4;
+        }
+        pressed
     }
-    pub fn set_joypad_set_select_up(&mut self) {
-        self.registers.joypad.set_bit(2, false)
+    fn get_joypad(&self) -> u8 {
+        // With neither group selected, a Super Game Boy answers with the current player
+        if let Some(sgb) = self.sgb.as_ref().filter(|_| self.registers.joypad == 0x30) {
+            return 0b11000000 | self.registers.joypad | sgb.joypad_id();
+        }
+        0b11000000 | self.registers.joypad | (!self.pressed_lines() & 0x0F)
+    }
+    pub fn cartridge_ram(&self) -> &[u8] {
+        &self.memory.eram
+    }
+    pub fn load_cartridge_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.memory.eram.len());
+        self.memory.eram[..len].copy_from_slice(&data[..len]);
+    }
+    /// Whether the cartridge RAM was written since the last call.
+    pub fn take_cartridge_ram_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.memory.eram_dirty)
+    }
+    pub fn tick_mbc(&mut self, cycles: usize) {
+        self.mbc.tick(cycles);
+    }
+    pub fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.mbc.rtc()
+    }
+    /// State of the cartridge rumble motor, always off for carts without one.
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+    /// 0xFF00-0xFF7F as the CPU reads them.
+    pub fn io_registers(&self) -> [u8; IO_REGISTERS_SIZE as usize] {
+        std::array::from_fn(|i| match IO_REGISTERS + i as u16 {
+            address @ WAVE_RAM..=WAVE_RAM_END => self.apu.wave_ram()[(address - WAVE_RAM) as usize],
+            address @ 0xFF51..=0xFF55 if self.cgb => self.hdma.register(address),
+            address => self.get(address),
+        })
+    }
+    /// Restores 0xFF00-0xFF7F from a snapshot, without starting a DMA or masking read-only bits.
+    pub fn set_io_registers(&mut self, registers: &[u8]) {
+        // Sound registers can only be written with the APU powered
+        if let Some(nr52) = registers.get((NR52 - IO_REGISTERS) as usize) {
+            self.apu.write(NR52, *nr52);
+        }
+        for (address, value) in (IO_REGISTERS..=IO_REGISTERS_END).zip(registers.iter().copied()) {
+            // Palette data goes through the index, which would move on with every write
+            if !matches!(address, 0xFF69 | 0xFF6B) {
+                self.restore_register(address, value);
+            }
+        }
     }
-    pub fn set_joypad_set_b_left(&mut self) {
-        self.registers.joypad.set_bit(1, false)
+    /// Writes registers the way `set_io_registers` does, for the state a boot ROM starts from or
+    /// leaves behind.
+    pub fn reset_registers(&mut self, registers: &[(u16, u8)]) {
+        for (address, value) in registers.iter().copied() {
+            self.restore_register(address, value);
+        }
     }
-    pub fn set_joypad_set_a_right(&mut self) {
-        self.registers.joypad.set_bit(0, false)
+    fn restore_register(&mut self, address: u16, value: u8) {
+        match address {
+            // Not a write the Super Game Boy should take as part of a packet
+            0xFF00 => self.registers.joypad = value & 0b00110000,
+            0xFF41 => self.registers.lcds = value,
+            0xFF44 => self.registers.ly = value,
+            0xFF46 | 0xFF50 => self.memory.set(address, value),
+            // Without the trigger bit, the channels stay as they are
+            0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => self.apu.write(address, value & 0x7F),
+            WAVE_RAM..=WAVE_RAM_END => self.apu.set_wave_ram_byte(address, value),
+            0xFF51..=0xFF55 if self.cgb => self.hdma.set_register(address, value),
+            0xFF4D if self.cgb => {
+                self.double_speed = value & 0x80 != 0;
+                self.speed_switch_armed = value & 0x01 != 0;
+            }
+            _ => self.set(address, value),
+        }
+    }
+    /// Copies blocks of VRAM DMA into the current bank, holding the CPU while it runs.
+    fn hdma_copy(&mut self, blocks: u8) {
+        for _ in 0..blocks {
+            let (source, destination) = self.hdma.next_block();
+            for i in 0..HDMA_BLOCK {
+                let value = self.get(source.wrapping_add(i));
+                self.memory.set(destination + i, value);
+            }
+            self.hdma_cycles += HDMA_BLOCK_CYCLES << self.double_speed as usize;
+        }
+    }
+    /// Copies the next block of a running HBlank transfer, the PPU calls this entering HBlank.
+    pub fn hblank_dma(&mut self) {
+        if self.cgb && self.hdma.active() {
+            self.hdma_copy(1);
+        }
     }
-    pub fn reset_joypad_buttons(&mut self) {
-        self.registers.joypad = self.registers.joypad | 0x0F;
+    /// Runs STOP, which resets DIV. Returns whether the speed was switched, after which the CPU
+    /// carries on, rather than entering low-power mode.
+    pub fn stop(&mut self) -> bool {
+        self.registers.div = 0;
+        self.div_reset = true;
+        if self.cgb && self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_switch_armed = false;
+            return true;
+        }
+        false
+    }
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+    /// A selected button is pressed, which ends low-power mode.
+    pub fn joypad_line_low(&self) -> bool {
+        self.pressed_lines() != 0
+    }
+    /// Whether STOP reset DIV since the last call, the emulator then restarts the count towards
+    /// its next increment.
+    pub(crate) fn take_div_reset(&mut self) -> bool {
+        std::mem::take(&mut self.div_reset)
+    }
+    /// M-cycles the CPU spent waiting on VRAM DMA since the last call.
+    pub(crate) fn take_dma_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.hdma_cycles)
+    }
+    /// The DMG boot ROM covers 0x0000-0x00FF, the CGB one continues at 0x0200-0x08FF and leaves
+    /// the cartridge header in between visible.
+    fn boot_rom_covers(&self, address: u16) -> bool {
+        self.boot_rom_mapped
+            && !(0x0100..=0x01FF).contains(&address)
+            && self.boot_rom.as_ref().is_some_and(|boot_rom| (address as usize) < boot_rom.len())
+    }
+    /// Maps a boot ROM over the start of the cartridge until 0xFF50 is written.
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom.into());
+        self.boot_rom_mapped = true;
+        self.memory.set(0xFF50, 0x00);
+    }
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+    /// VRAM as the PPU sees it, from either bank whatever VBK selects for the CPU.
+    pub fn vram(&self, bank: u8, address: u16) -> u8 {
+        self.memory.vram[(bank as usize & 1) * VRAM_SIZE as usize + (address - VRAM) as usize]
+    }
+    /// Whether sprites overlap by X coordinate first rather than by OAM index alone.
+    pub fn obj_priority_by_x(&self) -> bool {
+        !self.cgb || self.obj_priority_by_x
+    }
+    /// Register writes that put the cartridge back in its current banking state.
+    pub fn mbc_registers(&self) -> Vec<(u16, u8)> {
+        self.mbc.registers(&self.memory)
+    }
+    /// The pressed buttons belong to the host and are left alone.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.registers.sb);
+        state.u8(self.registers.sc);
+        state.u8(self.registers.div);
+        state.u8(self.registers.tima);
+        state.u8(self.registers.tma);
+        state.u8(self.registers.tca);
+        state.u8(self.registers.ly);
+        state.u8(self.registers.joypad);
+        state.u8(self.registers.scy);
+        state.u8(self.registers.scx);
+        state.u8(self.registers.wx);
+        state.u8(self.registers.wy);
+        state.u8(self.registers.lcdc);
+        state.u8(self.registers.lcds);
+        state.u8(self.registers.bg_palette_data);
+        state.u8(self.registers.obj_palette_0);
+        state.u8(self.registers.obj_palette_1);
+        state.u8(self.registers.interrupt_enable);
+        state.u8(self.registers.interrupt_flag);
+        state.bool(self.cgb);
+        self.bg_palettes.save_state(state);
+        self.obj_palettes.save_state(state);
+        state.bool(self.obj_priority_by_x);
+        self.hdma.save_state(state);
+        state.u64(self.hdma_cycles as u64);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+        state.u8(self.ppu_state.clone() as u8);
+        state.bytes(&self.fifo);
+        state.u16(self.dma_address);
+        state.bool(self.boot_rom_mapped);
+        self.memory.save_state(state);
+        self.mbc.save_state(state);
+        self.apu.save_state(state);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.sb = state.u8()?;
+        self.registers.sc = state.u8()?;
+        self.registers.div = state.u8()?;
+        self.registers.tima = state.u8()?;
+        self.registers.tma = state.u8()?;
+        self.registers.tca = state.u8()?;
+        self.registers.ly = state.u8()?;
+        self.registers.joypad = state.u8()?;
+        self.registers.scy = state.u8()?;
+        self.registers.scx = state.u8()?;
+        self.registers.wx = state.u8()?;
+        self.registers.wy = state.u8()?;
+        self.registers.lcdc = state.u8()?;
+        self.registers.lcds = state.u8()?;
+        self.registers.bg_palette_data = state.u8()?;
+        self.registers.obj_palette_0 = state.u8()?;
+        self.registers.obj_palette_1 = state.u8()?;
+        self.registers.interrupt_enable = state.u8()?;
+        self.registers.interrupt_flag = state.u8()?;
+        self.cgb = state.bool()?;
+        self.bg_palettes.load_state(state)?;
+        self.obj_palettes.load_state(state)?;
+        self.obj_priority_by_x = state.bool()?;
+        self.hdma.load_state(state)?;
+        self.hdma_cycles = state.u64()? as usize;
+        self.double_speed = state.bool()?;
+        self.speed_switch_armed = state.bool()?;
+        self.sgb = match state.bool()? {
+            true => {
+                let mut sgb = self.sgb.take().unwrap_or_else(|| Box::new(Sgb::new()));
+                sgb.load_state(state)?;
+                Some(sgb)
+            }
+            false => None,
+        };
+        self.ppu_state = PpuState::from_mode(state.u8()?)?;
+        self.fifo = state.vec()?;
+        self.dma_address = state.u16()?;
+        // The boot ROM is not part of the state, like the cartridge ROM
+        self.boot_rom_mapped = state.bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::NoBootRom);
+        }
+        self.memory.load_state(state)?;
+        self.mbc.load_state(state)?;
+        self.apu.load_state(state)
     }
-    pub fn load_rom(&mut self, buffer: Vec<u8>) {
-        self.memory.load_rom(buffer);
+    pub fn load_rom(&mut self, buffer: Vec<u8>, header: &Header) -> Result<(), LoadError> {
+        self.memory.load_rom(buffer, header)?;
 
-        match self._get(0x0147) {
+        match header.cartridge_type {
             0x00 => {
                 self.mbc = Box::new(MBC0::new());
             },
             0x01 | 0x02 | 0x03 => {
-                self.mbc = Box::new(MBC1::new());
+                self.mbc = Box::new(MBC1::new(MBC1::is_multicart(&self.memory.rom)));
             },
             0x05 | 0x06 => {
                 self.mbc = Box::new(MBC2::new());
             },
-            0x0F | 0x10 | 0x11 | 0x12 | 0x13 => {
-                self.mbc = Box::new(MBC3::new());
+            0x0F | 0x10 => {
+                self.mbc = Box::new(MBC3::new(true));
             }
-            _ => {
-                panic!("MBC not implemented yet! {:#02x}", self._get(0x147))
+            0x11..=0x13 => {
+                self.mbc = Box::new(MBC3::new(false));
+            }
+            0x19..=0x1B => {
+                self.mbc = Box::new(MBC5::new(false));
+            }
+            0x1C..=0x1E => {
+                self.mbc = Box::new(MBC5::new(true));
+            }
+            code => {
+                return Err(LoadError::UnsupportedCartridgeType(code))
             }
         }
 
         self.memory.set(0xFF40, 0x91);
         self.memory.set(0xFF00, 0x00);
+        Ok(())
     }
 }
 
@@ -496,6 +834,8 @@ impl Bus {
 #[cfg(test)]
 mod tests {
     use crate::bus::{Bus};
+    use crate::input::Button;
+    use crate::sgb::Sgb;
 
     #[test]
     fn rlc() {
@@ -554,4 +894,98 @@ mod tests {
         let mut bus = Bus::new();
         assert_eq!(bus.get_ly(), 91);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn joypad() {
+        let mut bus = Bus::new();
+        bus.press(Button::Start);
+        bus.press(Button::Left);
+        assert!(bus.get_int_request_joypad());
+
+        bus.set(0xFF00, 0x10);
+        assert_eq!(bus.get(0xFF00), 0xD7);
+        bus.set(0xFF00, 0x20);
+        assert_eq!(bus.get(0xFF00), 0xED);
+
+        bus.release(Button::Left);
+        assert_eq!(bus.get(0xFF00), 0xEF);
+    }
+
+    #[test]
+    fn sgb_joypad() {
+        let mut bus = Bus::new();
+        bus.sgb = Some(Box::new(Sgb::new()));
+        bus.press_player(1, Button::A);
+        bus.set(0xFF00, 0x30);
+        assert_eq!(bus.get(0xFF00), 0xFF);
+
+        // MLT_REQ for two players, sent a bit at a time
+        let mut packet = [0u8; 16];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 0x01;
+        bus.set(0xFF00, 0x00);
+        bus.set(0xFF00, 0x30);
+        for bit in 0..128 {
+            bus.set(0xFF00, if packet[bit / 8] >> (bit % 8) & 0x01 != 0 { 0x10 } else { 0x20 });
+            bus.set(0xFF00, 0x30);
+        }
+        bus.set(0xFF00, 0x20);
+        bus.set(0xFF00, 0x30);
+        assert_eq!(bus.get(0xFF00), 0xFF);
+
+        // Reading the buttons and letting P15 go high moves on to the second controller
+        bus.set(0xFF00, 0x10);
+        assert_eq!(bus.get(0xFF00), 0xDF);
+        bus.set(0xFF00, 0x30);
+        assert_eq!(bus.get(0xFF00), 0xFE);
+        // The ID is no pressed button, so it does not end STOP
+        assert!(!bus.joypad_line_low());
+        bus.set(0xFF00, 0x10);
+        assert_eq!(bus.get(0xFF00), 0xDE);
+
+        // Buttons of a controller the game is not reading raise no interrupt
+        bus.set_int_request_joypad(false);
+        bus.press_player(2, Button::B);
+        assert!(!bus.get_int_request_joypad());
+        bus.press_player(1, Button::B);
+        assert!(bus.get_int_request_joypad());
+    }
+
+    #[test]
+    fn vram_dma() {
+        let mut bus = Bus::new();
+        bus.set(0xFF55, 0x00);
+        assert_eq!(bus.get(0xFF55), 0xFF);
+        assert_eq!(bus.take_dma_cycles(), 0);
+
+        bus.cgb = true;
+        for i in 0..0x40 {
+            bus.set(0xC000 + i, i as u8 + 1);
+        }
+        for (address, value) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x01), (0xFF54, 0x00)] {
+            bus.set(address, value);
+        }
+        assert_eq!(bus.get(0xFF51), 0xFF);
+        bus.set(0xFF4F, 0x01);
+        bus.set(0xFF55, 0x01);
+        assert_eq!(bus.get(0xFF55), 0xFF);
+        assert_eq!(bus.take_dma_cycles(), 16);
+        assert_eq!(bus.get(0x8100), 0x01);
+        assert_eq!(bus.get(0x811F), 0x20);
+        bus.set(0xFF4F, 0x00);
+        assert_eq!(bus.get(0x8100), 0x00);
+
+        // Source and destination carry on where the last transfer stopped
+        bus.set(0xFF55, 0x81);
+        assert_eq!(bus.get(0xFF55), 0x01);
+        bus.hblank_dma();
+        assert_eq!(bus.get(0xFF55), 0x00);
+        assert_eq!(bus.get(0x8120), 0x21);
+        assert_eq!(bus.get(0x8130), 0x00);
+        bus.set(0xFF55, 0x00);
+        assert_eq!(bus.get(0xFF55), 0x80);
+        bus.hblank_dma();
+        assert_eq!(bus.get(0x8130), 0x00);
+        assert_eq!(bus.take_dma_cycles(), 8);
+    }
+}
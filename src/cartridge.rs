@@ -0,0 +1,229 @@
+use crate::error::LoadError;
+
+pub const HEADER_START: usize = 0x0100;
+pub const HEADER_END: usize = 0x014F;
+
+/// Logo at 0x0104-0x0133 that the boot ROM compares before starting a cartridge.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Parsed cartridge header, the 0x0100-0x014F region of bank 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    pub new_licensee_code: String,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination: u8,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    computed_header_checksum: u8,
+    computed_global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, LoadError> {
+        if rom.len() <= HEADER_END {
+            return Err(LoadError::Truncated { expected: HEADER_END + 1, actual: rom.len() });
+        }
+        let cgb_flag = rom[0x0143];
+
+        // Newer carts took the last bytes of the title for the manufacturer code and the CGB flag
+        let manufacturer = &rom[0x013F..0x0143];
+        let (title_end, manufacturer_code) = if cgb_flag & 0x80 != 0 {
+            if manufacturer.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+                (0x013F, Some(String::from_utf8_lossy(manufacturer).into_owned()))
+            } else {
+                (0x0143, None)
+            }
+        } else {
+            (0x0144, None)
+        };
+        let title = rom[0x0134..title_end]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| if c.is_ascii_graphic() || *c == b' ' { *c as char } else { '?' })
+            .collect::<String>();
+
+        Ok(Header {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            new_licensee_code: String::from_utf8_lossy(&rom[0x0144..0x0146]).into_owned(),
+            sgb_flag: rom[0x0146],
+            cartridge_type: rom[0x0147],
+            rom_size: rom[0x0148],
+            ram_size: rom[0x0149],
+            destination: rom[0x014A],
+            old_licensee_code: rom[0x014B],
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16,
+            computed_header_checksum: rom[0x0134..=0x014C]
+                .iter()
+                .fold(0u8, |checksum, byte| checksum.wrapping_sub(*byte).wrapping_sub(1)),
+            computed_global_checksum: rom
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+                .fold(0u16, |checksum, (_, byte)| checksum.wrapping_add(*byte as u16)),
+        })
+    }
+
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    /// Real hardware never checks this one, so plenty of homebrew gets it wrong.
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    pub fn computed_header_checksum(&self) -> u8 {
+        self.computed_header_checksum
+    }
+
+    pub fn computed_global_checksum(&self) -> u16 {
+        self.computed_global_checksum
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    /// SGB functions are only enabled with flag 0x03 and the old licensee code 0x33.
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    /// Whether the cartridge RAM, and the clock if it has one, survive power off.
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF)
+    }
+
+    pub fn japanese(&self) -> bool {
+        self.destination == 0x00
+    }
+
+    /// Number of 16 KiB ROM banks declared by the header, from 2 (32 KiB) up to 512 (8 MiB).
+    pub fn rom_banks(&self) -> Result<usize, LoadError> {
+        match self.rom_size {
+            0x00..=0x08 => Ok(2 << self.rom_size),
+            code => Err(LoadError::UnsupportedRomSize(code)),
+        }
+    }
+
+    /// External RAM size in bytes, MBC2 declares none but has 512 nibbles built in.
+    pub fn ram_bytes(&self) -> Result<usize, LoadError> {
+        if self.cartridge_type == 0x05 || self.cartridge_type == 0x06 {
+            return Ok(512);
+        }
+        match self.ram_size {
+            0x00 => Ok(0),
+            0x01 => Ok(2 * 1024),
+            0x02 => Ok(8 * 1024),
+            0x03 => Ok(32 * 1024),
+            0x04 => Ok(128 * 1024),
+            0x05 => Ok(64 * 1024),
+            code => Err(LoadError::UnsupportedRamSize(code)),
+        }
+    }
+
+    /// Number of 8 KiB external RAM banks, a smaller chip still takes up a whole bank.
+    pub fn ram_banks(&self) -> Result<usize, LoadError> {
+        Ok(self.ram_bytes()?.div_ceil(8 * 1024))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Header;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x013F].copy_from_slice(b"POKEMON_SLV");
+        rom[0x013F..0x0143].copy_from_slice(b"AAXE");
+        rom[0x0143] = 0x80;
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[0x0146] = 0x03;
+        rom[0x0147] = 0x10;
+        rom[0x0148] = 0x05;
+        rom[0x0149] = 0x03;
+        rom[0x014A] = 0x01;
+        rom[0x014B] = 0x33;
+        rom[0x014C] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn parse() {
+        let header = Header::parse(&rom()).unwrap();
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer_code, Some("AAXE".to_string()));
+        assert_eq!(header.new_licensee_code, "01");
+        assert!(header.supports_cgb());
+        assert!(!header.cgb_only());
+        assert!(header.supports_sgb());
+        assert!(!header.japanese());
+        assert_eq!(header.cartridge_type, 0x10);
+        assert_eq!(header.rom_banks().unwrap(), 64);
+        assert_eq!(header.ram_banks().unwrap(), 4);
+        assert_eq!(header.version, 0x02);
+        assert!(header.has_battery());
+    }
+
+    #[test]
+    fn sizes() {
+        let mut rom = rom();
+        for (code, banks) in [(0x00, 2), (0x02, 8), (0x05, 64), (0x08, 512)] {
+            rom[0x0148] = code;
+            assert_eq!(Header::parse(&rom).unwrap().rom_banks().unwrap(), banks);
+        }
+        for (code, bytes, banks) in [(0x00, 0, 0), (0x01, 0x800, 1), (0x03, 0x8000, 4), (0x04, 0x20000, 16), (0x05, 0x10000, 8)] {
+            rom[0x0149] = code;
+            let header = Header::parse(&rom).unwrap();
+            assert_eq!(header.ram_bytes().unwrap(), bytes);
+            assert_eq!(header.ram_banks().unwrap(), banks);
+        }
+        rom[0x0148] = 0x09;
+        assert!(Header::parse(&rom).unwrap().rom_banks().is_err());
+    }
+
+    #[test]
+    fn old_title() {
+        let mut rom = rom();
+        rom[0x0134..0x0144].copy_from_slice(b"TETRIS\0\0\0\0\0\0\0\0\0\0");
+        let header = Header::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer_code, None);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = rom();
+        let header = Header::parse(&rom).unwrap();
+        assert!(!header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+
+        rom[0x014D] = header.computed_header_checksum();
+        let global = Header::parse(&rom).unwrap().computed_global_checksum();
+        rom[0x014E] = (global >> 8) as u8;
+        rom[0x014F] = global as u8;
+        let header = Header::parse(&rom).unwrap();
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+    }
+}
@@ -0,0 +1,189 @@
+/// Colours the CGB boot ROM colourises DMG cartridges with, four per palette.
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+/// Offsets into `COLORS` of the OBJ0, OBJ1 and BG palettes of each combination. Most start on a
+/// palette, a few start one colour early like they do in the boot ROM.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (4 * 4, 4 * 4, 29 * 4), (18 * 4, 18 * 4, 18 * 4), (20 * 4, 20 * 4, 20 * 4), (24 * 4, 24 * 4, 24 * 4),
+    (9 * 4, 9 * 4, 9 * 4), (0, 0, 0), (27 * 4, 27 * 4, 27 * 4), (5 * 4, 5 * 4, 5 * 4),
+    (12 * 4, 12 * 4, 12 * 4), (26 * 4, 26 * 4, 26 * 4), (16 * 4, 8 * 4, 8 * 4), (4 * 4, 28 * 4, 28 * 4),
+    (4 * 4, 2 * 4, 2 * 4), (3 * 4, 4 * 4, 4 * 4), (4 * 4, 29 * 4, 29 * 4), (28 * 4, 4 * 4, 28 * 4),
+    (2 * 4, 17 * 4, 2 * 4), (16 * 4, 16 * 4, 8 * 4), (4 * 4, 4 * 4, 7 * 4), (4 * 4, 4 * 4, 18 * 4),
+    (4 * 4, 4 * 4, 20 * 4), (19 * 4, 19 * 4, 9 * 4), (4 * 4 - 1, 4 * 4 - 1, 11 * 4), (17 * 4, 17 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 2 * 4), (4 * 4, 4 * 4, 3 * 4), (28 * 4, 28 * 4, 0), (3 * 4, 3 * 4, 0),
+    (0, 0, 4), (18 * 4, 22 * 4, 18 * 4), (20 * 4, 22 * 4, 20 * 4), (24 * 4, 22 * 4, 24 * 4),
+    (16 * 4, 22 * 4, 8 * 4), (17 * 4, 4 * 4, 13 * 4), (28 * 4 - 1, 0, 14 * 4), (28 * 4 - 1, 4 * 4, 15 * 4),
+    (19 * 4, 22 * 4, 9 * 4), (16 * 4, 28 * 4, 10 * 4), (4 * 4, 23 * 4, 28 * 4), (17 * 4, 22 * 4, 2 * 4),
+    (4 * 4, 0, 2 * 4), (4 * 4, 28 * 4, 3 * 4), (28 * 4, 3 * 4, 0), (3 * 4, 28 * 4, 4 * 4),
+    (21 * 4, 28 * 4, 4 * 4), (3 * 4, 28 * 4, 0), (25 * 4, 3 * 4, 28 * 4), (0, 28 * 4, 8 * 4),
+    (4 * 4, 3 * 4, 28 * 4), (28 * 4, 3 * 4, 6 * 4), (4 * 4, 28 * 4, 29 * 4),
+];
+
+/// Title checksums of Nintendo's DMG releases and the combination each gets.
+const CHECKSUMS: [(u8, u8); 65] = [
+    (0x00, 0), (0x88, 4), (0x16, 5), (0x36, 35), (0xD1, 34), (0xDB, 3), (0xF2, 31), (0x3C, 15),
+    (0x8C, 10), (0x92, 5), (0x3D, 19), (0x5C, 36), (0x58, 7), (0xC9, 37), (0x3E, 30), (0x70, 44),
+    (0x1D, 21), (0x59, 32), (0x69, 31), (0x19, 20), (0x35, 5), (0xA8, 33), (0x14, 13), (0xAA, 14),
+    (0x75, 5), (0x95, 29), (0x99, 5), (0x34, 18), (0x6F, 9), (0x15, 3), (0xFF, 2), (0x97, 26),
+    (0x4B, 25), (0x90, 25), (0x17, 41), (0x10, 42), (0x39, 26), (0xF7, 45), (0xF6, 42), (0xA2, 45),
+    (0x49, 36), (0x4E, 38), (0x43, 26), (0x68, 42), (0xE0, 30), (0x8B, 41), (0xF0, 34), (0xCE, 34),
+    (0x0C, 5), (0x29, 42), (0xE8, 6), (0xB7, 5), (0x86, 33), (0x9A, 25), (0x52, 42), (0x01, 42),
+    (0x9D, 40), (0x71, 2), (0x9C, 16), (0xBD, 25), (0x5D, 42), (0x6D, 42), (0x67, 5), (0x3F, 0),
+    (0x6B, 39),
+];
+
+/// Checksums several titles share, told apart by the fourth letter of the title.
+const DUPLICATE_CHECKSUMS: [(u8, u8, u8); 29] = [
+    (0xB3, b'B', 36), (0x46, b'E', 22), (0x28, b'F', 25), (0xA5, b'A', 6), (0xC6, b'A', 32),
+    (0xD3, b'R', 12), (0x27, b'B', 36), (0x61, b'E', 11), (0x18, b'K', 39), (0x66, b'E', 18),
+    (0x6A, b'K', 39), (0xBF, b' ', 24), (0x0D, b'R', 31), (0xF4, b'-', 50), (0xB3, b'U', 17),
+    (0x46, b'R', 46), (0x28, b'A', 6), (0xA5, b'R', 27), (0xC6, b' ', 0), (0xD3, b'I', 47),
+    (0x27, b'N', 41), (0x61, b'A', 41), (0x18, b'I', 0), (0x66, b'L', 0), (0x6A, b'I', 19),
+    (0xBF, b'C', 34), (0x0D, b'E', 23), (0xF4, b' ', 18), (0xB3, b'R', 29),
+];
+
+/// Direction and button held at the CGB boot logo to choose the colours of a DMG cartridge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl PaletteCombo {
+    fn combination(&self) -> usize {
+        match self {
+            PaletteCombo::Up => 5,
+            PaletteCombo::UpA => 43,
+            PaletteCombo::UpB => 28,
+            PaletteCombo::Left => 48,
+            PaletteCombo::LeftA => 40,
+            PaletteCombo::LeftB => 7,
+            PaletteCombo::Down => 8,
+            PaletteCombo::DownA => 3,
+            PaletteCombo::DownB => 49,
+            PaletteCombo::Right => 1,
+            PaletteCombo::RightA => 0,
+            PaletteCombo::RightB => 6,
+        }
+    }
+
+    pub fn palettes(&self) -> CompatPalettes {
+        CompatPalettes::combination(self.combination())
+    }
+}
+
+/// RGB555 colours for BGP, OBP0 and OBP1 to pick from, the boot ROM writes them to BG palette 0
+/// and OBJ palettes 0 and 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatPalettes {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatPalettes {
+    fn combination(index: usize) -> CompatPalettes {
+        let (obj0, obj1, bg) = COMBINATIONS[index];
+        let colors = |offset: usize| std::array::from_fn(|i| COLORS[offset + i]);
+        CompatPalettes {
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        }
+    }
+
+    /// The colours the boot ROM picks for a cartridge. Nintendo's own titles are looked up by the
+    /// sum of their title bytes, every other cartridge gets the Right + A colours.
+    pub fn automatic(rom: &[u8]) -> CompatPalettes {
+        let nintendo = match rom[0x014B] {
+            0x33 => rom[0x0144..0x0146] == *b"01",
+            licensee => licensee == 0x01,
+        };
+        if !nintendo {
+            return PaletteCombo::RightA.palettes();
+        }
+        let checksum = rom[0x0134..0x0144].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let fourth_letter = rom[0x0137];
+        let combination = CHECKSUMS.iter()
+            .find(|(sum, _)| *sum == checksum)
+            .map(|(_, combination)| *combination)
+            .or_else(|| DUPLICATE_CHECKSUMS.iter()
+                .find(|(sum, letter, _)| *sum == checksum && *letter == fourth_letter)
+                .map(|(_, _, combination)| *combination))
+            .unwrap_or(0);
+        CompatPalettes::combination(combination as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compat::{CompatPalettes, PaletteCombo};
+
+    fn rom(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn automatic() {
+        let mut red = rom(b"POKEMON RED", 0x33);
+        red[0x0144..0x0146].copy_from_slice(b"01");
+        let palettes = CompatPalettes::automatic(&red);
+        assert_eq!(palettes.bg, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+
+        // Same checksum, told apart by the fourth letter
+        let blue = CompatPalettes::automatic(&rom(b"POKEMON BLUE", 0x01));
+        assert_eq!(blue.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        let vegas = CompatPalettes::automatic(&rom(b"VEGAS STAKES", 0x01));
+        assert_eq!(vegas.bg, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+
+        // The title alone is not enough for other publishers
+        assert_eq!(CompatPalettes::automatic(&rom(b"POKEMON RED", 0x08)), PaletteCombo::RightA.palettes());
+        assert_eq!(CompatPalettes::automatic(&rom(b"HOMEBREWS", 0x01)), PaletteCombo::RightA.palettes());
+        assert_eq!(PaletteCombo::Up.palettes().obj1, [0x7FFF, 0x32BF, 0x00D0, 0x0000]);
+    }
+}
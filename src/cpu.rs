@@ -1,6 +1,8 @@
 use crate::bus::{Bus, INT_ENABLE, INT_REQUEST};
 use crate::register::Register;
+use crate::state::{StateError, StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct Cpu {
     a: Register,
     b: Register,
@@ -15,12 +17,14 @@ pub struct Cpu {
     counter: usize,
     ime: bool,
     halted: bool,
+    /// In low-power mode after STOP, the emulator wakes it on a joypad line going low.
+    stopped: bool,
 }
 
 impl Cpu {
     // Constructor for Cpu
     pub fn new() -> Cpu {
-        let mut cpu = Cpu { a: Register { value: 0 }, b: Register { value: 0 }, c: Register { value: 0 }, d: Register { value: 0 }, e: Register { value: 0 }, f: Register { value: 0 }, h: Register { value: 0 }, l: Register { value: 0 }, pc: 0, sp: 0, counter: 0, ime: false, halted: false };
+        let mut cpu = Cpu { a: Register { value: 0 }, b: Register { value: 0 }, c: Register { value: 0 }, d: Register { value: 0 }, e: Register { value: 0 }, f: Register { value: 0 }, h: Register { value: 0 }, l: Register { value: 0 }, pc: 0, sp: 0, counter: 0, ime: false, halted: false, stopped: false };
         cpu.set_sp(crate::bus::HRAM_END);
         cpu.set_pc(0x0100);
         cpu.set_bc(0x0013);
@@ -191,13 +195,64 @@ impl Cpu {
     pub fn set_ime(&mut self, value: bool) {
         self.ime = value
     }
-    pub fn get_ime(&mut self) -> bool {
+    pub fn get_ime(&self) -> bool {
         self.ime
     }
+    /// PC, AF, BC, DE, HL and SP, the order BESS stores them in.
+    pub fn registers(&self) -> [u16; 6] {
+        [self.get_pc(), self.get_af(), self.get_bc(), self.get_de(), self.get_hl(), self.get_sp()]
+    }
+    pub fn set_registers(&mut self, [pc, af, bc, de, hl, sp]: [u16; 6]) {
+        self.set_pc(pc);
+        self.set_af(af);
+        self.set_bc(bc);
+        self.set_de(de);
+        self.set_hl(hl);
+        self.set_sp(sp);
+    }
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+    pub fn set_stopped(&mut self, stopped: bool) {
+        self.stopped = stopped;
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for register in [&self.a, &self.b, &self.c, &self.d, &self.e, &self.f, &self.h, &self.l] {
+            state.u8(register.get());
+        }
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.usize(self.counter);
+        state.bool(self.ime);
+        state.bool(self.halted);
+        state.bool(self.stopped);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for register in [&mut self.a, &mut self.b, &mut self.c, &mut self.d, &mut self.e, &mut self.f, &mut self.h, &mut self.l] {
+            register.set(state.u8()?);
+        }
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        self.counter = state.usize()?;
+        self.ime = state.bool()?;
+        self.halted = state.bool()?;
+        self.stopped = state.bool()?;
+        Ok(())
+    }
     fn misc(&mut self, inst: (u8, u8), mut bus: &mut Bus) -> bool {
         match inst {
             (0, 0) => {}
-            (1, 0) => { self._pc(1) },
+            // Switches speed when KEY1 asks for it on a CGB, enters low-power mode otherwise
+            (1, 0) => {
+                self._pc(1);
+                self.stopped = !bus.stop();
+            },
             (7, 6) => self.halted = true,
             (0xf, 3) => self.set_ime(false),
             (0xf, 0xb) => self.set_ime(true),
@@ -1,147 +1,1214 @@
+use crate::apu::NR52;
+use crate::audio::AudioSink;
+use crate::bess;
 use crate::bus::Bus;
+use crate::cartridge::Header;
+use crate::compat::{CompatPalettes, PaletteCombo};
 use crate::cpu::Cpu;
-use crate::input::Input;
+use crate::error::LoadError;
+use crate::input::{self, Button, Input};
+use crate::model::Model;
 use crate::output::Output;
-use crate::ppu::{Ppu, PpuState};
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::output::{dummy, Hotkey};
+use crate::rewind::RewindBuffer;
+use crate::rtc::RtcClock;
+use crate::sgb::{Sgb, BORDER_HEIGHT, BORDER_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 use bitfield::Bit;
 use macroquad::prelude::next_frame;
+use std::fs;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::{io, thread, time};
 use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
+
+/// T-cycles in one frame: 154 lines of 456 dots.
+pub const CYCLES_PER_FRAME: usize = 70224;
+/// T-cycles per second of the DMG clock.
+pub const CLOCK_SPEED: usize = 4194304;
+/// Frames per second of real hardware, about 59.73 Hz.
+pub const FRAME_RATE: f64 = CLOCK_SPEED as f64 / CYCLES_PER_FRAME as f64;
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 8.0;
 
 pub struct Emulator<I: Input> {
     cpu: Cpu,
     bus: Bus,
     ppu: Ppu,
     output: Box<dyn Output>,
+    audio: Option<Box<dyn AudioSink>>,
     input: I,
-    fps: Vec<f64>,
+    header: Header,
+    model: Model,
+    speed: Option<f64>,
+    speed_percent: f64,
+    timer: u64,
+    serial: Vec<u8>,
+    rumble: bool,
+    save_path: Option<PathBuf>,
+    frames_since_save: usize,
+    rom_path: Option<PathBuf>,
+    rewind: Option<RewindBuffer>,
+    rewinding: bool,
 }
 
 impl<I: Input> Emulator<I> {
-    pub fn new(rom_path: &str, input: I, output: Box<dyn Output>) -> Self {
-        let rom = File::open(rom_path).expect("Could not open rom");
+    pub fn from_path(rom_path: impl AsRef<Path>, input: I, output: Box<dyn Output>) -> Result<Self, LoadError> {
+        let rom = File::open(&rom_path)?;
 
+        let mut reader = BufReader::new(rom);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        let mut emulator = Emulator::from_bytes(buffer, input, output)?;
+        emulator.rom_path = Some(rom_path.as_ref().to_path_buf());
+        if emulator.header.has_battery() {
+            emulator.save_path = Some(rom_path.as_ref().with_extension("sav"));
+            emulator.load_save()?;
+        }
+        Ok(emulator)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>, input: I, output: Box<dyn Output>) -> Result<Self, LoadError> {
         let mut bus = Bus::new();
         let cpu = Cpu::new();
         let ppu = Ppu::new();
 
-        let mut reader = BufReader::new(rom);
-        let mut buffer = Vec::new();
-        let result = reader.read_to_end(&mut buffer);
-        match result {
-            Ok(_) => {}
-            Err(_) => {
-                panic!("oops")
+        let header = Header::parse(&rom)?;
+        bus.load_rom(rom, &header)?;
+
+        let mut emulator = Emulator {
+            cpu,
+            bus,
+            ppu,
+            output,
+            audio: None,
+            input,
+            header,
+            model: Model::Dmg,
+            speed: Some(1.0),
+            speed_percent: 0.0,
+            timer: 0,
+            serial: vec![],
+            rumble: false,
+            save_path: None,
+            frames_since_save: 0,
+            rom_path: None,
+            rewind: None,
+            rewinding: false,
+        };
+        emulator.set_model(if emulator.header.supports_cgb() { Model::Cgb } else { Model::Dmg });
+        Ok(emulator)
+    }
+
+    /// Puts the machine in the state the boot ROM of `model` hands over in, as if it had just
+    /// been switched on. Loaded machines start as a CGB when the header asks for one and as a
+    /// DMG otherwise. A CGB runs other cartridges like a DMG, in the colours its boot ROM picks.
+    /// A Super Game Boy takes commands from cartridges that ask for it and shows them in a border.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        let cgb = model.is_cgb() && self.header.supports_cgb();
+        self.bus.cgb = cgb;
+        self.ppu.cgb_mode = cgb;
+        self.ppu.compat_mode = model.is_cgb() && !cgb;
+        self.set_compat_palettes(None);
+        self.bus.sgb = (model.is_sgb() && self.header.supports_sgb()).then(|| Box::new(Sgb::new()));
+        self.set_frame_size();
+        self.cpu.set_registers(model.post_boot_registers(self.header.header_checksum));
+        self.cpu.set_ime(false);
+        self.cpu.set_halted(false);
+        self.bus.reset_registers(&model.post_boot_io());
+        let divider = model.post_boot_divider();
+        self.bus.registers.div = (divider >> 8) as u8;
+        self.timer = (divider & 0xFF) as u64 / 4;
+        // The mode bits follow the PPU, which starts the line over
+        self.ppu.restart_line(&mut self.bus);
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Tells the output how large frames are, which the border of a Super Game Boy surrounds.
+    fn set_frame_size(&mut self) {
+        match self.bus.sgb {
+            Some(_) => self.output.set_frame_size(BORDER_WIDTH as u16, BORDER_HEIGHT as u16),
+            None => self.output.set_frame_size(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16),
+        }
+    }
+
+    /// Colours a DMG cartridge on a CGB with the palettes of a button combination, or with the
+    /// ones the boot ROM looks up for the title. Does nothing for other machines.
+    pub fn set_compat_palettes(&mut self, combo: Option<PaletteCombo>) {
+        if !self.ppu.compat_mode {
+            return;
+        }
+        let palettes = match combo {
+            Some(combo) => combo.palettes(),
+            None => CompatPalettes::automatic(&self.bus.memory.rom),
+        };
+        self.bus.bg_palettes.set_palette(0, palettes.bg);
+        self.bus.obj_palettes.set_palette(0, palettes.obj0);
+        self.bus.obj_palettes.set_palette(1, palettes.obj1);
+    }
+
+    /// Switches the machine on with the boot ROM of its model over the start of the cartridge,
+    /// which scrolls the logo and checks the header before handing over at 0x0100.
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), LoadError> {
+        if boot_rom.len() != self.model.boot_rom_size() {
+            return Err(LoadError::BootRomSize(boot_rom.len()));
+        }
+        self.cpu.set_registers([0; 6]);
+        self.cpu.set_ime(false);
+        self.cpu.set_halted(false);
+        self.bus.reset_registers(&self.model.power_on_io());
+        self.bus.apu.write(NR52, 0x00);
+        self.bus.registers.div = 0;
+        self.timer = 0;
+        self.bus.map_boot_rom(boot_rom);
+        self.ppu.restart_line(&mut self.bus);
+        Ok(())
+    }
+
+    /// Runs until the PPU enters VBlank, or for one frame worth of cycles if it never does.
+    pub fn run_frame(&mut self) {
+        for (button, pressed) in self.output.take_keys() {
+            self.input.key(button, pressed);
+        }
+        self.input.check_input(&mut self.bus);
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            cycles += self.step() * 4;
+            if self.ppu.frame_complete {
+                self.ppu.frame_complete = false;
+                break;
             }
         }
-        bus.load_rom(buffer);
+        if let Some(sgb) = &mut self.bus.sgb {
+            sgb.end_frame(&self.ppu.framebuffer, &mut self.output);
+        }
 
-        bus.set_int_enable_lcd(true);
-        bus.set_int_enable_joypad(true);
-        bus.set_int_enable_serial(true);
-        bus.set_int_enable_vblank(true);
-        bus.set_int_enable_timer(true);
+        if self.rewind.as_mut().is_some_and(|rewind| rewind.frame()) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        }
 
-        bus.set_int_request_lcd(false);
-        bus.set_int_request_joypad(false);
-        bus.set_int_request_serial(false);
-        bus.set_int_request_vblank(false);
-        bus.set_int_request_timer(false);
+        if let Some(audio) = &mut self.audio {
+            audio.write_samples(&self.bus.apu.take_samples());
+        }
 
-        Emulator {
-            cpu,
+        if self.bus.take_cartridge_ram_dirty() {
+            self.frames_since_save = self.frames_since_save.max(1);
+        }
+        if self.frames_since_save > 0 {
+            self.frames_since_save += 1;
+            // A failed write is tried again a second later, `flush_save` reports it
+            if self.frames_since_save > FRAME_RATE as usize && self.save().is_err() {
+                self.frames_since_save = 1;
+            }
+        }
+    }
+
+    /// Copies the running machine for search and training, see `fork_with`.
+    pub fn fork(&self) -> Emulator<input::Dummy> {
+        self.fork_with(input::Dummy::new(), Box::new(dummy::Dummy::new()))
+    }
+
+    /// Copies the running machine with its own input and output, the ROM is shared rather than
+    /// copied. The copy runs uncapped, has no rewind history or audio and never writes save files.
+    pub fn fork_with<J: Input>(&self, input: J, output: Box<dyn Output>) -> Emulator<J> {
+        let mut bus = self.bus.clone();
+        bus.apu.set_sample_rate(None);
+        let mut emulator = Emulator {
+            cpu: self.cpu.clone(),
             bus,
-            ppu,
+            ppu: self.ppu.clone(),
             output,
+            audio: None,
             input,
-            fps: vec![],
+            header: self.header.clone(),
+            model: self.model,
+            speed: None,
+            speed_percent: 0.0,
+            timer: self.timer,
+            serial: vec![],
+            rumble: self.rumble,
+            save_path: None,
+            frames_since_save: 0,
+            rom_path: None,
+            rewind: None,
+            rewinding: false,
+        };
+        emulator.set_frame_size();
+        emulator
+    }
+
+    /// Swaps the output, returning the previous one.
+    pub fn set_output(&mut self, output: Box<dyn Output>) -> Box<dyn Output> {
+        let previous = std::mem::replace(&mut self.output, output);
+        self.set_frame_size();
+        previous
+    }
+
+    /// Keeps a snapshot every `interval` frames for `rewind`, within about `budget` bytes.
+    /// A budget of 0 turns rewinding off.
+    pub fn set_rewind(&mut self, interval: usize, budget: usize) {
+        self.rewind = (budget > 0).then(|| RewindBuffer::new(interval, budget));
+    }
+
+    /// Steps back to the previous snapshot and shows its frame, false when there is none left.
+    pub fn rewind(&mut self) -> bool {
+        let Some(state) = self.rewind.as_mut().and_then(|rewind| rewind.pop()) else {
+            return false;
+        };
+        self.load_state(&state).expect("rewind snapshots are made by this machine");
+        if let Some(sgb) = &self.bus.sgb {
+            sgb.render(&mut self.output);
+            return true;
+        }
+        for i in 0..self.ppu.framebuffer.len() {
+            let (x, y) = ((i % SCREEN_WIDTH) as u16, (i / SCREEN_WIDTH) as u16);
+            match self.ppu.cgb_mode || self.ppu.compat_mode {
+                true => self.output.write_color(x, y, self.ppu.color_framebuffer[i]),
+                false => self.output.write_pixel(x, y, self.ppu.framebuffer[i], false, 0),
+            }
+        }
+        true
+    }
+
+    /// Where battery-backed RAM is stored, `from_path` picks the ROM path with a `.sav` extension.
+    pub fn set_save_path(&mut self, path: Option<PathBuf>) {
+        self.save_path = path;
+    }
+
+    /// Loads battery-backed RAM in the raw format of other emulators, a missing file is not an error.
+    pub fn load_save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        self.bus.load_cartridge_ram(&data);
+        let ram = self.bus.cartridge_ram().len();
+        if let Some(rtc) = self.bus.rtc() {
+            if data.len() > ram {
+                rtc.load_footer(&data[ram..]);
+            }
+        }
+        self.bus.take_cartridge_ram_dirty();
+        Ok(())
+    }
+
+    /// Writes battery-backed RAM, with the clock state appended for MBC3 timer carts.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        let mut data = self.bus.cartridge_ram().to_vec();
+        if let Some(rtc) = self.bus.rtc() {
+            data.extend_from_slice(&rtc.to_footer());
+        }
+        fs::write(path, data)?;
+        self.frames_since_save = 0;
+        Ok(())
+    }
+
+    /// Writes battery-backed RAM if it changed since the last write, for frontends to call
+    /// before exiting.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        let dirty = self.bus.take_cartridge_ram_dirty() || self.frames_since_save > 0;
+        if !dirty {
+            return Ok(());
+        }
+        self.save().inspect_err(|_| self.frames_since_save = self.frames_since_save.max(1))
+    }
+
+    /// Title and checksum of the loaded ROM, states only load on the ROM they were made with.
+    fn rom_identity(&self) -> String {
+        format!("{} ({:04X})", self.header.title, self.header.computed_global_checksum())
+    }
+
+    /// Snapshots the whole machine, the ROM itself is only referenced by its title and checksum.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        for byte in STATE_MAGIC {
+            state.u8(*byte);
+        }
+        state.u32(STATE_VERSION);
+        state.bytes(self.rom_identity().as_bytes());
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        state.u64(self.timer);
+        state.bool(self.rumble);
+        state.into_inner()
+    }
+
+    /// Restores a snapshot from `save_state`, on any error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup).expect("a state just saved loads");
         }
+        // States of a Super Game Boy carry its border
+        self.set_frame_size();
+        result
     }
 
-    pub fn run(&mut self, max_cycles: usize, stdout: &mut dyn Write) {
+    fn read_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in magic.iter_mut() {
+            *byte = state.u8().map_err(|_| StateError::NotAState)?;
+        }
+        if &magic != STATE_MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = state.u32()?;
+        if version != STATE_VERSION {
+            return Err(StateError::IncompatibleVersion { found: version, expected: STATE_VERSION });
+        }
+        let found = String::from_utf8_lossy(state.bytes()?).into_owned();
+        let expected = self.rom_identity();
+        if found != expected {
+            return Err(StateError::RomMismatch { expected, found });
+        }
+
+        self.cpu.load_state(&mut state)?;
+        self.bus.load_state(&mut state)?;
+        self.ppu.load_state(&mut state)?;
+        self.timer = state.u64()?;
+        self.rumble = state.bool()?;
+        state.finish()?;
+        self.output.set_rumble(self.rumble);
+        Ok(())
+    }
+
+    /// Writes the machine in the BESS format shared with SameBoy and other emulators.
+    pub fn export_bess(&mut self) -> Vec<u8> {
+        bess::export(&self.cpu, &mut self.bus, &self.header)
+    }
+
+    /// Reads a BESS state written by this or another emulator, on any error the machine is left as it was.
+    pub fn import_bess(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = bess::import(data, &mut self.cpu, &mut self.bus, &mut self.ppu);
+        if result.is_err() {
+            self.read_state(&backup).expect("a state just saved loads");
+        }
+        result
+    }
+
+    /// Numbered state files live next to the ROM, `game.gb` gets `game.ss1` and so on.
+    pub fn state_slot_path(&self, slot: u8) -> Option<PathBuf> {
+        self.rom_path.as_ref().map(|path| path.with_extension(format!("ss{}", slot)))
+    }
+
+    pub fn save_state_slot(&mut self, slot: u8) -> Result<(), StateError> {
+        let path = self.state_slot_path(slot).ok_or(StateError::NoRomPath)?;
+        fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    pub fn load_state_slot(&mut self, slot: u8) -> Result<(), StateError> {
+        let path = self.state_slot_path(slot).ok_or(StateError::NoRomPath)?;
+        let data = fs::read(path)?;
+        self.load_state(&data)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.ppu.framebuffer
+    }
+
+    /// RGB555 colours of the last frame, only drawn on a CGB.
+    pub fn color_framebuffer(&self) -> &[u16] {
+        &self.ppu.color_framebuffer
+    }
+
+    /// Whether the machine is a CGB running a cartridge made for it.
+    pub fn cgb_mode(&self) -> bool {
+        self.bus.cgb
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.bus.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.bus.release(button);
+    }
+
+    /// Presses a button on another controller, which a Super Game Boy reads once the game asks
+    /// for several players. Player 0 is the one `press` uses.
+    pub fn press_player(&mut self, player: usize, button: Button) {
+        self.bus.press_player(player, button);
+    }
+
+    pub fn release_player(&mut self, player: usize, button: Button) {
+        self.bus.release_player(player, button);
+    }
+
+    pub fn read_memory(&self, address: u16) -> u8 {
+        self.bus.get(address)
+    }
+
+    pub(crate) fn write_memory(&mut self, address: u16, value: u8) {
+        self.bus.set(address, value);
+    }
+
+    /// Arms KEY1 and runs STOP, like a program switching speed does. Only a CGB switches.
+    pub(crate) fn switch_speed(&mut self) {
+        self.bus.set(0xFF4D, 0x01);
+        self.bus.stop();
+    }
+
+    /// Jumps into a subroutine with `a` in A and a fresh stack at `sp`, it returns to `return_address`.
+    pub(crate) fn call(&mut self, address: u16, a: u8, sp: u16, return_address: u16) {
+        let sp = sp.wrapping_sub(2);
+        self.bus.set(sp, return_address as u8);
+        self.bus.set(sp.wrapping_add(1), (return_address >> 8) as u8);
+        let [_, af, bc, de, hl, _] = self.cpu.registers();
+        self.cpu.set_registers([address, (a as u16) << 8 | af & 0xFF, bc, de, hl, sp]);
+        self.cpu.set_halted(false);
+        self.cpu.set_ime(false);
+    }
+
+    /// Chooses what drives the cartridge clock, does nothing for carts without one.
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = self.bus.rtc() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    /// Mixes sound at this many stereo samples per second for `take_audio_samples`, `None` turns mixing off.
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Hands the sound of every frame to a sink at the rate it asks for, returning the previous
+    /// sink after finishing it.
+    pub fn set_audio_sink(&mut self, audio: Option<Box<dyn AudioSink>>) -> Option<Box<dyn AudioSink>> {
+        self.bus.apu.set_sample_rate(audio.as_ref().map(|audio| audio.sample_rate()));
+        let mut previous = std::mem::replace(&mut self.audio, audio);
+        if let Some(previous) = &mut previous {
+            if let Err(error) = previous.finish() {
+                eprintln!("Could not finish audio: {}", error);
+            }
+        }
+        previous
+    }
+
+    /// Interleaved left and right samples between -1 and 1 produced since the last call.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.bus.apu.take_samples()
+    }
+
+    /// Returns the bytes sent over the serial port since the last call.
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.serial)
+    }
+
+    /// Sets the speed as a multiple of real hardware, clamped to 0.25x-8x. `None` runs uncapped.
+    pub fn set_speed(&mut self, speed: Option<f64>) {
+        self.speed = speed.map(|speed| speed.clamp(MIN_SPEED, MAX_SPEED));
+    }
+
+    pub fn get_speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    /// Measured speed as a percentage of real hardware, updated about twice a second by `run`.
+    pub fn speed_percent(&self) -> f64 {
+        self.speed_percent
+    }
+
+    pub fn run(&mut self, max_frames: usize, stdout: &mut dyn Write) {
         let mut count: usize = 0;
-        let mut timer: u64 = 0;
+        let start = Instant::now();
+        let mut next_frame = start;
+        let mut window_start = start;
+        let mut window_frames = 0;
         while self.output.refresh() {
-            let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
-            for i in 0..17476 {
-                self.input.check_input(&mut self.bus);
-                let cycles = match self.cpu.get_ime() {
-                    true => {
-                        if self.bus.get_int_enable_vblank() && self.bus.get_int_request_vblank() {
-                            self.bus.set_int_request_vblank(false);
-                            self.cpu.interrupt(&mut self.bus, 0x40)
-                        } else if self.bus.get_int_enable_lcd() && self.bus.get_int_request_lcd() {
-                            self.bus.set_int_request_lcd(false);
-                            self.cpu.interrupt(&mut self.bus, 0x48)
-                        } else if self.bus.get_int_enable_timer() && self.bus.get_int_request_timer()
-                        {
-                            self.bus.set_int_request_timer(false);
-                            self.cpu.interrupt(&mut self.bus, 0x50)
-                        } else if self.bus.get_int_enable_serial() && self.bus.get_int_request_serial()
-                        {
-                            self.bus.set_int_request_serial(false);
-                            self.cpu.interrupt(&mut self.bus, 0x58)
-                        } else if self.bus.get_int_enable_joypad() && self.bus.get_int_request_joypad()
-                        {
-                            self.bus.set_int_request_joypad(false);
-                            self.cpu.interrupt(&mut self.bus, 0x60)
-                        } else {
-                            self.cpu.step(&mut self.bus, true)
-                        }
-                    }
-                    false => self.cpu.step(&mut self.bus, true),
-                };
-                timer += cycles as u64;
+            // Keeps showing the oldest snapshot once there is nothing further back
+            if !(self.rewinding && self.rewind()) {
+                self.run_frame();
+            }
 
-                self.ppu.tick(&mut self.bus, &mut self.output, cycles);
+            let serial = self.take_serial_output();
+            if !serial.is_empty() {
+                stdout.write_all(&serial).expect("Couldn't write");
+                stdout.flush().expect("Couldn't flush");
+            }
 
-                for _ in 1..=cycles {
-                    if timer % 64 == 0 {
-                        self.bus.registers.div = self.bus.registers.div.wrapping_add(1);
-                    }
+            count += 1;
+            if count >= max_frames && max_frames != 0 {
+                let speed = count as f64 / start.elapsed().as_secs_f64() / FRAME_RATE * 100.0;
+                println!("Avg speed: {:.0}%", speed);
+                break;
+            }
 
-                    if self.bus.registers.tca.bit(2) {
-                        let step_size = match self.bus.registers.tca & 0x3 {
-                            0 => 256,
-                            1 => 4,
-                            2 => 16,
-                            3 => 64,
-                            _ => panic!("Should be impossible!"),
-                        };
-                        if timer % step_size == 0 {
-                            let val = self.bus.registers.tima.wrapping_add(1);
-                            self.bus.registers.tima = val;
-                            if val == 0 {
-                                self.bus.registers.tima = self.bus.registers.tma;
-                                self.bus.set_int_request_timer(true);
-                            }
-                        }
+            if let Some(speed) = self.speed {
+                next_frame += Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+                let now = Instant::now();
+                if next_frame > now {
+                    sleep(next_frame - now);
+                } else if now - next_frame > Duration::from_millis(100) {
+                    // Too far behind to catch up, continue from now instead of rushing frames
+                    next_frame = now;
+                }
+            } else {
+                next_frame = Instant::now();
+            }
+
+            window_frames += 1;
+            let elapsed = window_start.elapsed();
+            if elapsed >= Duration::from_millis(500) {
+                self.speed_percent = window_frames as f64 / elapsed.as_secs_f64() / FRAME_RATE * 100.0;
+                window_start = Instant::now();
+                window_frames = 0;
+            }
+            self.output.set_diagnostics(format!("Speed: {:.0}%", self.speed_percent));
+
+            for hotkey in self.output.take_hotkeys() {
+                let result = match hotkey {
+                    Hotkey::SaveState(slot) => self.save_state_slot(slot),
+                    Hotkey::LoadState(slot) => self.load_state_slot(slot),
+                    Hotkey::Rewind(held) => {
+                        self.rewinding = held;
+                        Ok(())
                     }
+                    Hotkey::SpeedUp => {
+                        self.set_speed(self.speed.map(|speed| speed * 2.0));
+                        Ok(())
+                    }
+                    Hotkey::SlowDown => {
+                        self.set_speed(Some(self.speed.map_or(MAX_SPEED, |speed| speed / 2.0)));
+                        Ok(())
+                    }
+                    Hotkey::Uncapped => {
+                        self.set_speed(if self.speed.is_some() { None } else { Some(1.0) });
+                        Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    eprintln!("{:?} failed: {}", hotkey, error);
                 }
+            }
+        }
+    }
 
-                if self.bus.registers.sc == 0x81 {
-                    write!(stdout, "{}", self.bus.registers.sb as char).expect("Couldn't write");
-                    stdout.flush().expect("Couldn't flush");
-                    self.bus.registers.sc = 0;
+    /// Runs an instruction or interrupt and returns the M-cycles that took at normal speed.
+    fn step(&mut self) -> usize {
+        // The oscillator is off in low-power mode, time only passes for the frame pacing
+        if self.cpu.stopped() {
+            if !self.bus.joypad_line_low() {
+                return 1;
+            }
+            self.cpu.set_stopped(false);
+        }
+        let cycles = match self.cpu.get_ime() {
+            true => {
+                if self.bus.get_int_enable_vblank() && self.bus.get_int_request_vblank() {
+                    self.bus.set_int_request_vblank(false);
+                    self.cpu.interrupt(&mut self.bus, 0x40)
+                } else if self.bus.get_int_enable_lcd() && self.bus.get_int_request_lcd() {
+                    self.bus.set_int_request_lcd(false);
+                    self.cpu.interrupt(&mut self.bus, 0x48)
+                } else if self.bus.get_int_enable_timer() && self.bus.get_int_request_timer()
+                {
+                    self.bus.set_int_request_timer(false);
+                    self.cpu.interrupt(&mut self.bus, 0x50)
+                } else if self.bus.get_int_enable_serial() && self.bus.get_int_request_serial()
+                {
+                    self.bus.set_int_request_serial(false);
+                    self.cpu.interrupt(&mut self.bus, 0x58)
+                } else if self.bus.get_int_enable_joypad() && self.bus.get_int_request_joypad()
+                {
+                    self.bus.set_int_request_joypad(false);
+                    self.cpu.interrupt(&mut self.bus, 0x60)
+                } else {
+                    self.cpu.step(&mut self.bus, true)
                 }
             }
-            count += 1;
-            if count > max_cycles && max_cycles != 0 {
-                println!("Avg FPS: {:}", self.fps.iter().sum::<f64>() / self.fps.len() as f64);
-                break;
+            false => self.cpu.step(&mut self.bus, true),
+        };
+        // DIV counts from 0 again after STOP, including the cycles towards its next increment
+        if self.bus.take_div_reset() {
+            self.timer -= self.timer % 64;
+        }
+        // The rest of the machine keeps running while VRAM DMA holds the CPU
+        let cycles = cycles + self.bus.take_dma_cycles();
+        let start = self.timer;
+        self.timer += cycles as u64;
+        // In double speed the CPU and timers run twice as fast as the rest
+        let ticks = match self.bus.double_speed() {
+            true => (self.timer / 2 - start / 2) as usize,
+            false => cycles,
+        };
+
+        self.ppu.tick(&mut self.bus, &mut self.output, ticks);
+        self.bus.tick_mbc(ticks * 4);
+        self.bus.apu.tick(ticks * 4);
+        // The frame sequencer keeps to 512 Hz by following the next bit of DIV
+        let sequencer_bit = if self.bus.double_speed() { 5 } else { 4 };
+
+        // DIV and TIMA count every M-cycle of the instruction, not only where it ended
+        for timer in start + 1..=self.timer {
+            if timer % 64 == 0 {
+                let div = self.bus.registers.div;
+                self.bus.registers.div = div.wrapping_add(1);
+                if div.bit(sequencer_bit) && !self.bus.registers.div.bit(sequencer_bit) {
+                    self.bus.apu.clock_frame_sequencer();
+                }
             }
 
-            let diff = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() - millis;
+            if self.bus.registers.tca.bit(2) {
+                let step_size = match self.bus.registers.tca & 0x3 {
+                    0 => 256,
+                    1 => 4,
+                    2 => 16,
+                    3 => 64,
+                    _ => panic!("Should be impossible!"),
+                };
+                if timer % step_size == 0 {
+                    let val = self.bus.registers.tima.wrapping_add(1);
+                    self.bus.registers.tima = val;
+                    if val == 0 {
+                        self.bus.registers.tima = self.bus.registers.tma;
+                        self.bus.set_int_request_timer(true);
+                    }
+                }
+            }
+        }
+
+        if self.bus.registers.sc == 0x81 {
+            self.serial.push(self.bus.registers.sb);
+            self.bus.registers.sc = 0;
+        }
+
+        let rumble = self.bus.rumble();
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            self.output.set_rumble(rumble);
+        }
+        ticks
+    }
+}
+
+impl<I: Input> Drop for Emulator<I> {
+    fn drop(&mut self) {
+        self.set_audio_sink(None);
+        // Forks never own a save file. Frontends that want the error call `flush_save` first.
+        if self.save_path.is_some() {
+            let _ = self.flush_save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::path::Path;
+    use std::rc::Rc;
+    use crate::audio::AudioSink;
+    use crate::compat::PaletteCombo;
+    use std::sync::Arc;
+    use crate::emulator::{Emulator, CLOCK_SPEED, CYCLES_PER_FRAME, MAX_SPEED};
+    use crate::error::LoadError;
+    use crate::input::{self, Button};
+    use crate::model::Model;
+    use crate::state::StateError;
+    use crate::output::dummy::Dummy;
+    use crate::output::{Hotkey, Output};
+
+    fn special_rom() -> Emulator<input::Dummy> {
+        Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("01-special.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap()
+    }
+
+    /// A cartridge without a header that spins on JR -2 from the entry point.
+    fn idle_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        rom
+    }
+
+    #[test]
+    fn frame_ends_at_vblank() {
+        let mut emu = special_rom();
+
+        for _ in 0..3 {
+            emu.run_frame();
+            assert_eq!(emu.read_memory(0xFF44), 144);
+        }
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut emu = special_rom();
+
+        emu.set_speed(Some(100.0));
+        assert_eq!(emu.get_speed(), Some(8.0));
+        emu.set_speed(Some(0.1));
+        assert_eq!(emu.get_speed(), Some(0.25));
+        emu.set_speed(None);
+        assert_eq!(emu.get_speed(), None);
+    }
+
+    #[test]
+    fn load_errors() {
+        let result = Emulator::from_bytes(vec![0; 0x100], input::Dummy::new(), Box::new(Dummy::new()));
+        assert!(matches!(result, Err(LoadError::Truncated { expected: 0x150, actual: 0x100 })));
+
+        let mut rom = idle_rom();
+        rom[0x0148] = 0x09;
+        let result = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new()));
+        assert!(matches!(result, Err(LoadError::UnsupportedRomSize(0x09))));
+
+        let mut rom = idle_rom();
+        rom[0x0149] = 0x06;
+        let result = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new()));
+        assert!(matches!(result, Err(LoadError::UnsupportedRamSize(0x06))));
+
+        let mut rom = idle_rom();
+        rom[0x0147] = 0xFC;
+        let result = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new()));
+        assert!(matches!(result, Err(LoadError::UnsupportedCartridgeType(0xFC))));
+
+        let result = Emulator::from_path("does-not-exist.gb", input::Dummy::new(), Box::new(Dummy::new()));
+        assert!(matches!(result, Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn short_rom_is_mirrored() {
+        let mut rom = vec![0; 0x6000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x02;
+        rom[0x4000] = 0x42;
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        assert_eq!(emu.read_memory(0x4000), 0x42);
+        assert_eq!(emu.read_memory(0x5FFF), 0x00);
+        assert_eq!(emu.read_memory(0x6000), 0xFF);
+
+        emu.bus.set(0x2000, 0x05);
+        assert_eq!(emu.read_memory(0x4000), 0x42);
+    }
+
+    #[test]
+    fn post_boot_state() {
+        let mut emu = Emulator::from_bytes(idle_rom(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        // A header checksum of 0 leaves the half carry and carry flags clear
+        assert_eq!(emu.cpu.registers(), [0x0100, 0x0180, 0x0013, 0x00D8, 0x014D, 0xFFFE]);
+        for (address, value) in [(0xFF04, 0xAB), (0xFF07, 0xF8), (0xFF0F, 0xE1), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF47, 0xFC), (0xFFFF, 0x00)] {
+            assert_eq!(emu.read_memory(address), value, "{:04X}", address);
+        }
+
+        emu.set_model(Model::Mgb);
+        assert_eq!(emu.cpu.registers()[1], 0xFF80);
+        emu.set_model(Model::Dmg0);
+        assert_eq!(emu.cpu.registers()[2..5], [0xFF13, 0x00C1, 0x8403]);
+        assert_eq!(emu.read_memory(0xFF04), 0x18);
+    }
+
+    #[test]
+    fn boot_rom() {
+        let rom = idle_rom();
+        let mut emu = Emulator::from_bytes(rom.clone(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        assert!(matches!(emu.set_boot_rom(vec![0; 0x200]), Err(LoadError::BootRomSize(0x200))));
+
+        // Writes a marker, then unmaps itself from the last two bytes like the real one
+        let mut boot_rom = vec![0; 0x100];
+        boot_rom[..5].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0]);
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        emu.set_boot_rom(boot_rom).unwrap();
+        assert_eq!(emu.cpu.registers()[0], 0x0000);
+        assert_eq!(emu.read_memory(0x0000), 0x3E);
+        assert_eq!(emu.read_memory(0xFF40), 0x00);
+        assert_eq!(emu.read_memory(0xFF26) & 0x80, 0x00);
+
+        let state = emu.save_state();
+        emu.run_frame();
+        assert_eq!(emu.read_memory(0xC000), 0x42);
+        assert_eq!(emu.read_memory(0x0000), 0x00);
+        assert_eq!(emu.cpu.registers()[0], 0x0100);
+
+        let mut other = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        assert!(matches!(other.load_state(&state), Err(StateError::NoBootRom)));
+    }
+
+    #[test]
+    fn cgb_mode() {
+        let mut rom = idle_rom();
+        rom[0x0143] = 0x80;
+        let mut emu = Emulator::from_bytes(rom.clone(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        assert_eq!(emu.model(), Model::Cgb);
+        assert!(emu.cgb_mode());
+        assert_eq!(emu.cpu.registers()[1], 0x1180);
+
+        // Colour 1 of BG palette 1 is red, the top left tile comes from bank 1 in that palette
+        emu.write_memory(0xFF68, 0x80 | 0x0A);
+        emu.write_memory(0xFF69, 0x1F);
+        emu.write_memory(0xFF69, 0x00);
+        emu.write_memory(0xFF4F, 0x01);
+        for row in 0..8 {
+            emu.write_memory(0x8000 + row * 2, 0xFF);
+        }
+        emu.write_memory(0x9800, 0x01 | 0x08);
+        emu.write_memory(0xFF4F, 0x00);
+        assert_eq!(emu.read_memory(0xFF4F), 0xFE);
+        assert_eq!(emu.read_memory(0x8000), 0x00);
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(emu.color_framebuffer()[0], 0x001F);
+        assert_eq!(emu.color_framebuffer()[8], 0x7FFF);
+        assert_eq!(emu.framebuffer()[0], 1);
+
+        let data = emu.export_bess();
+        let mut other = Emulator::from_bytes(rom.clone(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        other.import_bess(&data).unwrap();
+        assert_eq!(other.export_bess(), data);
+        other.set_model(Model::Dmg);
+        assert!(matches!(other.import_bess(&data), Err(StateError::UnsupportedModel(_))));
+
+        // Without the header flag even a CGB runs the cartridge like a DMG
+        rom[0x0143] = 0x00;
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.set_model(Model::Cgb);
+        assert!(!emu.cgb_mode());
+        assert_eq!(emu.read_memory(0xFF4F), 0xFF);
+        assert_eq!(emu.read_memory(0xFF69), 0xFF);
+    }
 
-            let time = 1_000_000.0 / diff as f64;
-            self.fps.push(time);
-            if time < 1_000_000.0 / 60.0 {
-                //sleep(Duration::from_micros((1_000_000.0 / 60.0 - time) as u64))
+    #[test]
+    fn compat_palettes() {
+        let mut emu = Emulator::from_bytes(idle_rom(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.set_model(Model::Cgb);
+        assert!(!emu.cgb_mode());
+
+        // Every tile is colour 1, which BGP turns into shade 1 of the boot ROM's default palette
+        for row in 0..8 {
+            emu.write_memory(0x8000 + row * 2, 0xFF);
+        }
+        emu.write_memory(0xFF47, 0xE4);
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(emu.color_framebuffer()[0], 0x1BEF);
+
+        emu.set_compat_palettes(Some(PaletteCombo::Down));
+        emu.run_frame();
+        assert_eq!(emu.color_framebuffer()[0], 0x4A5F);
+        emu.write_memory(0xFF47, 0xFC);
+        emu.run_frame();
+        assert_eq!(emu.color_framebuffer()[0], 0x0000);
+    }
+
+    struct Hotkeys(Option<Vec<Hotkey>>);
+
+    impl Output for Hotkeys {
+        fn refresh(&mut self) -> bool {
+            self.0.is_some()
+        }
+        fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+            self.0.take().unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn speed_hotkeys() {
+        let mut emu = Emulator::from_bytes(idle_rom(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.set_speed(None);
+        for (hotkeys, speed) in [
+            (vec![Hotkey::SlowDown, Hotkey::SlowDown], Some(4.0)),
+            (vec![Hotkey::SpeedUp, Hotkey::SpeedUp], Some(MAX_SPEED)),
+            (vec![Hotkey::Uncapped, Hotkey::SpeedUp], None),
+            (vec![Hotkey::Uncapped, Hotkey::SlowDown], Some(0.5)),
+        ] {
+            emu.set_output(Box::new(Hotkeys(Some(hotkeys))));
+            emu.run(0, &mut Vec::new());
+            assert_eq!(emu.get_speed(), speed);
+        }
+    }
+
+    struct Keys(Vec<(Button, bool)>);
+
+    impl Output for Keys {
+        fn take_keys(&mut self) -> Vec<(Button, bool)> {
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    #[test]
+    fn keyboard() {
+        let keys = Keys(vec![(Button::Start, true), (Button::A, true), (Button::A, false)]);
+        let mut emu = Emulator::from_bytes(idle_rom(), input::Keyboard::new(), Box::new(keys)).unwrap();
+        emu.run_frame();
+        emu.write_memory(0xFF00, 0x10);
+        assert_eq!(emu.read_memory(0xFF00), 0xD7);
+    }
+
+    struct Frame(Rc<RefCell<(u16, u16, Vec<u16>)>>);
+
+    impl Output for Frame {
+        fn set_frame_size(&mut self, width: u16, height: u16) {
+            *self.0.borrow_mut() = (width, height, vec![0; width as usize * height as usize]);
+        }
+        fn write_color(&mut self, x: u16, y: u16, color: u16) {
+            let mut frame = self.0.borrow_mut();
+            let width = frame.0 as usize;
+            frame.2[y as usize * width + x as usize] = color;
+        }
+    }
+
+    #[test]
+    fn sgb() {
+        let mut rom = idle_rom();
+        rom[0x0146] = 0x03;
+        rom[0x014B] = 0x33;
+        let frame = Rc::new(RefCell::new((0, 0, vec![])));
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Frame(frame.clone()))).unwrap();
+        assert_eq!(frame.borrow().0, 160);
+        emu.set_model(Model::Sgb);
+        assert_eq!((frame.borrow().0, frame.borrow().1), (256, 224));
+
+        // PAL01 with a red colour 0, sent through P1 like a game would
+        let mut packet = [0u8; 16];
+        packet[0] = 0x01;
+        packet[1] = 0x1F;
+        emu.write_memory(0xFF00, 0x00);
+        emu.write_memory(0xFF00, 0x30);
+        for bit in 0..128 {
+            emu.write_memory(0xFF00, if packet[bit / 8] >> (bit % 8) & 0x01 != 0 { 0x10 } else { 0x20 });
+            emu.write_memory(0xFF00, 0x30);
+        }
+        emu.write_memory(0xFF00, 0x20);
+        emu.write_memory(0xFF00, 0x30);
+        emu.run_frame();
+        emu.run_frame();
+        // The blank game screen and the empty border both show colour 0
+        assert_eq!(frame.borrow().2[40 * 256 + 48], 0x001F);
+        assert_eq!(frame.borrow().2[0], 0x001F);
+
+        let state = emu.save_state();
+        emu.set_model(Model::Dmg);
+        assert_eq!(frame.borrow().0, 160);
+        emu.load_state(&state).unwrap();
+        assert_eq!(frame.borrow().0, 256);
+        assert_eq!(emu.save_state(), state);
+    }
+
+    #[test]
+    fn div_between_instructions() {
+        let mut emu = Emulator::from_bytes(idle_rom(), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        // JR takes 3 M-cycles, passing the point where DIV goes up without ending on it
+        emu.bus.registers.div = 0;
+        emu.timer = 62;
+        emu.step();
+        assert_eq!(emu.timer, 65);
+        assert_eq!(emu.read_memory(0xFF04), 1);
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut rom = idle_rom();
+        // LD A, 1; LDH (KEY1), A; STOP; JR -2
+        rom[0x0100..0x0108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        rom[0x0143] = 0x80;
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.run_frame();
+        assert_eq!(emu.read_memory(0xFF4D), 0xFE);
+        assert!(!emu.cpu.stopped());
+
+        let start = emu.timer;
+        emu.run_frame();
+        let cycles = (emu.timer - start) as usize;
+        assert!(cycles.abs_diff(2 * CYCLES_PER_FRAME / 4) <= 4, "{}", cycles);
+    }
+
+    #[test]
+    fn stop() {
+        let mut rom = idle_rom();
+        // Selects the buttons, stops, then writes a marker once woken
+        rom[0x0100..0x010D].copy_from_slice(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.run_frame();
+        emu.run_frame();
+        assert!(emu.cpu.stopped());
+        assert_eq!(emu.read_memory(0xFF04), 0x00);
+        assert_eq!(emu.read_memory(0xC000), 0x00);
+
+        emu.bus.press(Button::Start);
+        emu.run_frame();
+        assert!(!emu.cpu.stopped());
+        assert_eq!(emu.read_memory(0xC000), 0x42);
+    }
+
+    #[test]
+    fn stop_div_phase() {
+        let mut rom = idle_rom();
+        // STOP with a pressed button selected, so the CPU carries on right away
+        rom[0x0100..0x0104].copy_from_slice(&[0x10, 0x00, 0x18, 0xFE]);
+        let mut emu = Emulator::from_bytes(rom, input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.bus.press(Button::Start);
+        emu.write_memory(0xFF00, 0x10);
+        emu.timer = 60;
+        emu.step();
+        assert!(emu.timer < 8, "{}", emu.timer);
+        // A full 64 M-cycles pass before DIV goes up, not the 4 that were left before STOP
+        while emu.timer < 60 {
+            emu.step();
+        }
+        assert_eq!(emu.read_memory(0xFF04), 0x00);
+        emu.step();
+        emu.step();
+        assert_eq!(emu.read_memory(0xFF04), 0x01);
+    }
+
+    #[test]
+    fn battery_save() {
+        let dir = std::env::temp_dir().join(format!("rusty-gb-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rom = idle_rom();
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        std::fs::write(dir.join("game.gb"), &rom).unwrap();
+
+        let mut emu = Emulator::from_path(dir.join("game.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.bus.set(0x0000, 0x0A);
+        emu.bus.set(0xA123, 0x42);
+        drop(emu);
+
+        let save = std::fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x123], 0x42);
+
+        let mut emu = Emulator::from_path(dir.join("game.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        emu.bus.set(0x0000, 0x0A);
+        assert_eq!(emu.read_memory(0xA123), 0x42);
+        assert!(emu.flush_save().is_ok());
+        // Nothing was written since loading, so dropping leaves the file alone
+        std::fs::write(dir.join("game.sav"), [0x99; 0x2000]).unwrap();
+        drop(emu);
+        assert_eq!(std::fs::read(dir.join("game.sav")).unwrap(), [0x99; 0x2000]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut emu = special_rom();
+        for _ in 0..30 {
+            emu.run_frame();
+        }
+        let state = emu.save_state();
+        for _ in 0..30 {
+            emu.run_frame();
+        }
+        let expected = emu.save_state();
+
+        let mut other = special_rom();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        for _ in 0..30 {
+            other.run_frame();
+        }
+        assert_eq!(other.save_state(), expected);
+        assert_eq!(other.framebuffer(), emu.framebuffer());
+    }
+
+    #[test]
+    fn save_state_rejected() {
+        let mut emu = special_rom();
+        emu.run_frame();
+        let state = emu.save_state();
+
+        let path = Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual");
+        let mut other = Emulator::from_path(path.join("02-interrupts.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        assert!(matches!(other.load_state(&state), Err(StateError::RomMismatch { .. })));
+
+        let mut old = state.clone();
+        old[4] = 0;
+        assert!(matches!(emu.load_state(&old), Err(StateError::IncompatibleVersion { found: 0, .. })));
+        assert!(matches!(emu.load_state(b"nope"), Err(StateError::NotAState)));
+
+        let before = emu.save_state();
+        assert!(matches!(emu.load_state(&state[..state.len() - 1]), Err(StateError::Corrupt)));
+        assert_eq!(emu.save_state(), before);
+    }
+
+    #[test]
+    fn rewind() {
+        let mut emu = special_rom();
+        assert!(!emu.rewind());
+        emu.set_rewind(5, 1 << 20);
+        let mut states = vec![];
+        for frame in 1..=20 {
+            emu.run_frame();
+            if frame % 5 == 0 {
+                states.push(emu.save_state());
             }
-            self.output.set_diagnostics(format!("FPS: {}", time).parse().unwrap());
         }
+        emu.run_frame();
+        for state in states.iter().rev() {
+            assert!(emu.rewind());
+            assert_eq!(&emu.save_state(), state);
+        }
+        assert!(!emu.rewind());
+    }
+
+    #[test]
+    fn fork() {
+        let mut emu = special_rom();
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        let mut fork = emu.fork();
+        assert!(Arc::ptr_eq(&fork.bus.memory.rom, &emu.bus.memory.rom));
+        assert_eq!(fork.save_state(), emu.save_state());
+
+        fork.bus.set(0xC000, 0x42);
+        assert_ne!(emu.read_memory(0xC000), 0x42);
+        fork.bus.set(0xC000, emu.read_memory(0xC000));
+        for _ in 0..10 {
+            emu.run_frame();
+            fork.run_frame();
+        }
+        assert_eq!(fork.save_state(), emu.save_state());
+    }
+
+    struct Samples(Rc<Cell<usize>>);
+
+    impl AudioSink for Samples {
+        fn sample_rate(&self) -> u32 {
+            32768
+        }
+        fn write_samples(&mut self, samples: &[f32]) {
+            self.0.set(self.0.get() + samples.len());
+        }
+    }
+
+    #[test]
+    fn audio_sink() {
+        let mut emu = special_rom();
+        let count = Rc::new(Cell::new(0));
+        emu.set_audio_sink(Some(Box::new(Samples(count.clone()))));
+        for _ in 0..60 {
+            emu.run_frame();
+        }
+        // Frames end at VBlank, so the first one is short
+        let expected = 2 * 60 * CYCLES_PER_FRAME * 32768 / CLOCK_SPEED;
+        assert!(count.get() <= expected && count.get() > expected - 2 * 32768 / 60);
+        assert!(emu.take_audio_samples().is_empty());
     }
 }
@@ -0,0 +1,42 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Truncated { expected: usize, actual: usize },
+    UnsupportedRomSize(u8),
+    UnsupportedRamSize(u8),
+    UnsupportedCartridgeType(u8),
+    InvalidGbs(&'static str),
+    BootRomSize(usize),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "could not read rom: {}", error),
+            LoadError::Truncated { expected, actual } => write!(f, "rom is truncated, expected {} bytes but got {}", expected, actual),
+            LoadError::UnsupportedRomSize(code) => write!(f, "unsupported rom size code {:#04x}", code),
+            LoadError::UnsupportedRamSize(code) => write!(f, "unsupported ram size code {:#04x}", code),
+            LoadError::UnsupportedCartridgeType(code) => write!(f, "unsupported cartridge type {:#04x}", code),
+            LoadError::InvalidGbs(reason) => write!(f, "not a usable GBS file: {}", reason),
+            LoadError::BootRomSize(size) => write!(f, "boot rom is {} bytes, expected 256 or 2304 for a CGB", size),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
@@ -1,7 +1,50 @@
 use crate::bus::{Bus, VRAM};
 use crate::ppu::OAM;
+use crate::state::{StateError, StateReader, StateWriter};
 
+/// Background map attributes in CGB mode, bank 1 of VRAM behind the tile IDs.
+pub(crate) const ATTR_PALETTE: u8 = 0x07;
+pub(crate) const ATTR_BANK: u8 = 0x08;
+pub(crate) const ATTR_FLIP_X: u8 = 0x20;
+pub(crate) const ATTR_FLIP_Y: u8 = 0x40;
+pub(crate) const ATTR_PRIORITY: u8 = 0x80;
 
+/// The attributes of a map entry, always 0 outside CGB mode.
+pub(crate) fn tile_attributes(bus: &Bus, address: u16) -> u8 {
+    if bus.cgb { bus.vram(1, address) } else { 0 }
+}
+
+/// One row of a background or window tile, leftmost pixel first. Besides its colour in bits 0-1
+/// each pixel keeps its palette in bits 2-4 and its priority over sprites in bit 7.
+pub(crate) fn tile_row(bus: &Bus, tile_id: u8, attributes: u8, tile_line: u8) -> [u8; 8] {
+    let offset = match bus.get_ldlc_bg_window_tiles() {
+        true => 0x8000 + tile_id as u16 * 16,
+        false => {
+            if tile_id <= 127 {
+                0x9000 + tile_id as u16 * 16
+            } else {
+                0x8000 + tile_id as u16 * 16
+            }
+        }
+    };
+    let line = match attributes & ATTR_FLIP_Y != 0 {
+        true => 7 - tile_line,
+        false => tile_line,
+    };
+    let address = offset + line as u16 * 2;
+    let bank = (attributes & ATTR_BANK != 0) as u8;
+    let value1 = bus.vram(bank, address);
+    let value2 = bus.vram(bank, address + 1);
+    let extra = (attributes & ATTR_PALETTE) << 2 | attributes & ATTR_PRIORITY;
+
+    let mut row: [u8; 8] = std::array::from_fn(|i| (value1 >> (7 - i)) & 1 | ((value2 >> (7 - i)) & 1) << 1 | extra);
+    if attributes & ATTR_FLIP_X != 0 {
+        row.reverse();
+    }
+    row
+}
+
+#[derive(Clone)]
 enum FetcherState {
     ReadTileData0,
     ReadTileData1,
@@ -9,12 +52,14 @@ enum FetcherState {
     ReadTileID,
 }
 
+#[derive(Clone)]
 pub struct Fetcher {
     ticks: usize,
     pub(crate) tile_map: u16,
     tile_data: u16,
     tile_index: u8,
     tile_id: u8,
+    attributes: u8,
     map_address: u16,
     tile_line: u8,
     line_index: u8,
@@ -36,6 +81,7 @@ impl Fetcher {
             map_address: 0,
             tile_line: 0,
             tile_id: 0,
+            attributes: 0,
             pixel_data: [0; 16],
             oams: vec![],
             fifo_bg: Vec::with_capacity(16),
@@ -45,6 +91,48 @@ impl Fetcher {
             tiles_set: true,
         }
     }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.usize(self.ticks);
+        state.u16(self.tile_map);
+        state.u16(self.tile_data);
+        state.u8(self.tile_index);
+        state.u8(self.tile_id);
+        state.u8(self.attributes);
+        state.u16(self.map_address);
+        state.u8(self.tile_line);
+        state.u8(self.line_index);
+        state.bool(self.tiles_set);
+        state.bytes(&self.pixel_data);
+        state.bytes(&self.fifo_bg);
+        state.u8(match self.state {
+            FetcherState::ReadTileData0 => 0,
+            FetcherState::ReadTileData1 => 1,
+            FetcherState::PushToFIFO => 2,
+            FetcherState::ReadTileID => 3,
+        });
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ticks = state.usize()?;
+        self.tile_map = state.u16()?;
+        self.tile_data = state.u16()?;
+        self.tile_index = state.u8()?;
+        self.tile_id = state.u8()?;
+        self.attributes = state.u8()?;
+        self.map_address = state.u16()?;
+        self.tile_line = state.u8()?;
+        self.line_index = state.u8()?;
+        self.tiles_set = state.bool()?;
+        state.bytes_into(&mut self.pixel_data)?;
+        self.fifo_bg = state.vec()?;
+        self.state = match state.u8()? {
+            0 => FetcherState::ReadTileData0,
+            1 => FetcherState::ReadTileData1,
+            2 => FetcherState::PushToFIFO,
+            3 => FetcherState::ReadTileID,
+            _ => return Err(StateError::Corrupt),
+        };
+        Ok(())
+    }
     pub fn tick(&mut self, bus: &mut Bus) {
         match self.state {
             FetcherState::ReadTileData0 => self.read_tile_data(bus),
@@ -55,23 +143,7 @@ impl Fetcher {
 
     fn read_tile_data(&mut self, bus: &Bus) {
         self.tiles_set = bus.get_ldlc_bg_window_tiles();
-        let offset = match self.tiles_set {
-            true => 0x8000 + self.tile_id as u16 * 16,
-            false => {
-                if self.tile_id <= 127 {
-                    0x9000 + self.tile_id as u16 * 16
-                } else {
-                    0x8000 + self.tile_id as u16 * 16
-                }
-            }
-        };
-        let address = offset + self.tile_line as u16 * 2;
-        let value1 = bus._get(address);
-        let value2 = bus._get(address + 1);
-
-        for bit in 0..=7 {
-            self.pixel_data[7 - bit] = (value1 >> bit) & 1 | ((value2 >> bit) & 1 ) << 1;
-        }
+        self.pixel_data[..8].copy_from_slice(&tile_row(bus, self.tile_id, self.attributes, self.tile_line));
 
         self.state = FetcherState::PushToFIFO;
     }
@@ -83,7 +155,9 @@ impl Fetcher {
         }
     }
     fn read_tile_id(&mut self, bus: &Bus) {
-        self.tile_id = bus._get(self.map_address + self.tile_index as u16 + self.line_index as u16 * 32);
+        let address = self.map_address + self.tile_index as u16 + self.line_index as u16 * 32;
+        self.tile_id = bus.vram(0, address);
+        self.attributes = tile_attributes(bus, address);
         self.pixel_data.fill(0);
         self.state = FetcherState:: ReadTileData0
     }
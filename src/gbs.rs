@@ -0,0 +1,326 @@
+use std::time::Duration;
+use crate::audio::AudioSink;
+use crate::emulator::{Emulator, CLOCK_SPEED, FRAME_RATE};
+use crate::error::LoadError;
+use crate::input;
+use crate::output::dummy::Dummy;
+
+pub const GBS_MAGIC: &[u8; 3] = b"GBS";
+const GBS_HEADER_SIZE: usize = 0x70;
+/// Where the machine waits between calls, in the unused space after the cartridge header.
+const IDLE: u16 = 0x0150;
+/// MBC5 with RAM, so rips can switch banks and keep state in A000-BFFF.
+const CARTRIDGE_TYPE: u8 = 0x1A;
+
+/// Game Boy Sound System rip: the music code of a game with a header saying how to drive it.
+pub struct Gbs {
+    pub version: u8,
+    pub songs: u8,
+    /// First song to play, counted from 1.
+    pub first_song: u8,
+    pub load: u16,
+    pub init: u16,
+    pub play: u16,
+    pub stack: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Zero padded Latin-1 field.
+fn text(data: &[u8]) -> String {
+    data.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect::<String>().trim().to_string()
+}
+
+impl Gbs {
+    pub fn parse(data: &[u8]) -> Result<Gbs, LoadError> {
+        if data.len() < GBS_HEADER_SIZE {
+            return Err(LoadError::Truncated { expected: GBS_HEADER_SIZE, actual: data.len() });
+        }
+        if &data[..3] != GBS_MAGIC {
+            return Err(LoadError::InvalidGbs("missing GBS signature"));
+        }
+        let gbs = Gbs {
+            version: data[0x03],
+            songs: data[0x04],
+            first_song: data[0x05],
+            load: u16_at(data, 0x06),
+            init: u16_at(data, 0x08),
+            play: u16_at(data, 0x0A),
+            stack: u16_at(data, 0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(&data[0x10..0x30]),
+            author: text(&data[0x30..0x50]),
+            copyright: text(&data[0x50..0x70]),
+            data: data[GBS_HEADER_SIZE..].to_vec(),
+        };
+        if gbs.version != 1 {
+            return Err(LoadError::InvalidGbs("only version 1 exists"));
+        }
+        if gbs.songs == 0 {
+            return Err(LoadError::InvalidGbs("no songs"));
+        }
+        if gbs.load < 0x0400 || gbs.load >= 0x8000 {
+            return Err(LoadError::InvalidGbs("load address outside 0400-7FFF"));
+        }
+        if gbs.load as usize + gbs.data.len() > 512 * 0x4000 {
+            return Err(LoadError::InvalidGbs("more than 8 MiB of data"));
+        }
+        Ok(gbs)
+    }
+
+    /// Whether the play routine runs on the timer rather than on every VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// Whether the rip runs on a CGB in double speed, which also doubles the timer rate.
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    /// Times per second the play routine is called.
+    pub fn play_rate(&self) -> f64 {
+        if !self.uses_timer() {
+            return FRAME_RATE;
+        }
+        let divider = [1024, 16, 64, 256][(self.timer_control & 0x03) as usize];
+        let speed = if self.double_speed() { 2 } else { 1 };
+        (speed * CLOCK_SPEED) as f64 / divider as f64 / (256 - self.timer_modulo as usize) as f64
+    }
+
+    /// The data at its load address in a cartridge of its own. The restart vectors jump to the
+    /// same offsets from the load address, the interrupts call the play routine and return to
+    /// a loop that halts until the next one.
+    pub fn rom(&self) -> Vec<u8> {
+        let size = (self.load as usize + self.data.len()).next_power_of_two().max(0x8000);
+        let mut rom = vec![0xFF; size];
+        for vector in (0x00..0x40).step_by(8) {
+            let target = self.load + vector as u16;
+            rom[vector..vector + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+        for vector in [0x40, 0x50] {
+            rom[vector..vector + 4].copy_from_slice(&[0xCD, self.play as u8, (self.play >> 8) as u8, 0xD9]);
+        }
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, IDLE as u8, (IDLE >> 8) as u8]);
+
+        let mut title = [0; 16];
+        for (byte, char) in title.iter_mut().zip(self.title.chars()) {
+            *byte = if char.is_ascii() { char as u8 } else { b'?' };
+        }
+        rom[0x0134..0x0144].copy_from_slice(&title);
+        // Only a CGB has double speed
+        if self.double_speed() {
+            rom[0x0143] = 0x80;
+        }
+        rom[0x0147] = CARTRIDGE_TYPE;
+        rom[0x0148] = (size / 0x8000).trailing_zeros() as u8;
+        rom[0x0149] = 0x02;
+        rom[0x014D] = rom[0x0134..=0x014C].iter().fold(0u8, |checksum, byte| checksum.wrapping_sub(*byte).wrapping_sub(1));
+
+        // EI, then HALT and JR back to it
+        rom[IDLE as usize..IDLE as usize + 4].copy_from_slice(&[0xFB, 0x76, 0x18, 0xFD]);
+
+        let load = self.load as usize;
+        rom[load..load + self.data.len()].copy_from_slice(&self.data);
+        rom
+    }
+}
+
+/// Plays the songs of a GBS rip on a machine with no screen or buttons.
+pub struct GbsPlayer {
+    gbs: Gbs,
+    emulator: Emulator<input::Dummy>,
+    power_on: Vec<u8>,
+    song: u8,
+}
+
+impl GbsPlayer {
+    /// Starts the first song.
+    pub fn new(gbs: Gbs) -> Result<GbsPlayer, LoadError> {
+        let emulator = Emulator::from_bytes(gbs.rom(), input::Dummy::new(), Box::new(Dummy::new()))?;
+        let power_on = emulator.save_state();
+        let mut player = GbsPlayer { song: gbs.first_song.saturating_sub(1), gbs, emulator, power_on };
+        player.start(player.song);
+        Ok(player)
+    }
+
+    pub fn gbs(&self) -> &Gbs {
+        &self.gbs
+    }
+
+    /// The song playing, counted from 0.
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    /// Resets the machine and runs the init routine of a song counted from 0.
+    pub fn start(&mut self, song: u8) {
+        self.song = song;
+        self.emulator.load_state(&self.power_on).expect("the power on state is made by this machine");
+        self.emulator.write_memory(0x0000, 0x0A);
+        if self.gbs.double_speed() {
+            self.emulator.switch_speed();
+        }
+        // TIMA starts at the modulo too, so the first call comes after one period like the rest
+        self.emulator.write_memory(0xFF05, self.gbs.timer_modulo);
+        self.emulator.write_memory(0xFF06, self.gbs.timer_modulo);
+        self.emulator.write_memory(0xFF07, self.gbs.timer_control & 0x07);
+        self.emulator.write_memory(0xFF0F, 0x00);
+        self.emulator.write_memory(0xFFFF, if self.gbs.uses_timer() { 0x04 } else { 0x01 });
+        self.emulator.call(self.gbs.init, song, self.gbs.stack, IDLE);
+    }
+
+    /// Runs the song for about one frame.
+    pub fn run_frame(&mut self) {
+        self.emulator.run_frame();
+    }
+
+    pub fn emulator(&self) -> &Emulator<input::Dummy> {
+        &self.emulator
+    }
+
+    /// Plays a song from its start for `length` into a sink, fading out linearly over the last
+    /// `fade` of it.
+    pub fn render(&mut self, song: u8, length: Duration, fade: Duration, sink: &mut dyn AudioSink) {
+        let rate = sink.sample_rate();
+        self.emulator.set_sample_rate(Some(rate));
+        self.start(song);
+        self.emulator.take_audio_samples();
+
+        let total = (length.as_secs_f64() * rate as f64) as usize;
+        let fade_start = total.saturating_sub((fade.as_secs_f64() * rate as f64) as usize);
+        let mut written = 0;
+        while written < total {
+            self.emulator.run_frame();
+            let mut samples = self.emulator.take_audio_samples();
+            samples.truncate((total - written) * 2);
+            for (i, frame) in samples.chunks_mut(2).enumerate() {
+                let position = written + i;
+                if position >= fade_start {
+                    let gain = (total - position) as f32 / (total - fade_start) as f32;
+                    frame.iter_mut().for_each(|sample| *sample *= gain);
+                }
+            }
+            written += samples.len() / 2;
+            sink.write_samples(&samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::audio::AudioSink;
+    use crate::emulator::FRAME_RATE;
+    use crate::error::LoadError;
+    use crate::gbs::{Gbs, GbsPlayer};
+
+    /// Init stores the song in C000 and starts a square wave, play counts its calls in C001.
+    fn rip(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x70];
+        data[..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x0E].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x20, 0x04, 0xFE, 0xFF]);
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        let mut init = vec![0xEA, 0x00, 0xC0];
+        for (register, value) in [(0x12, 0xF0), (0x11, 0x80), (0x13, 0x00), (0x14, 0x87)] {
+            init.extend_from_slice(&[0x3E, value, 0xE0, register]);
+        }
+        init.push(0xC9);
+        init.resize(0x20, 0x00);
+        data.extend_from_slice(&init);
+        data.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    struct Samples(Vec<f32>);
+
+    impl AudioSink for Samples {
+        fn sample_rate(&self) -> u32 {
+            8000
+        }
+        fn write_samples(&mut self, samples: &[f32]) {
+            self.0.extend_from_slice(samples);
+        }
+    }
+
+    #[test]
+    fn header() {
+        let gbs = Gbs::parse(&rip(0, 0)).unwrap();
+        assert_eq!((gbs.songs, gbs.first_song), (3, 2));
+        assert_eq!((gbs.load, gbs.init, gbs.play, gbs.stack), (0x0400, 0x0400, 0x0420, 0xFFFE));
+        assert_eq!(gbs.title, "Test");
+        assert!(!gbs.uses_timer());
+
+        let mut data = rip(0, 0);
+        data[0] = b'X';
+        assert!(matches!(Gbs::parse(&data), Err(LoadError::InvalidGbs(_))));
+        assert!(matches!(Gbs::parse(&data[..0x20]), Err(LoadError::Truncated { .. })));
+    }
+
+    #[test]
+    fn play_on_vblank() {
+        let mut player = GbsPlayer::new(Gbs::parse(&rip(0, 0)).unwrap()).unwrap();
+        assert_eq!(player.song(), 1);
+        for _ in 0..60 {
+            player.run_frame();
+        }
+        assert_eq!(player.emulator().read_memory(0xC000), 1);
+        assert!((58..=61).contains(&player.emulator().read_memory(0xC001)));
+
+        player.start(2);
+        player.run_frame();
+        assert_eq!(player.emulator().read_memory(0xC000), 2);
+        assert!(player.emulator().read_memory(0xC001) <= 1);
+    }
+
+    #[test]
+    fn play_on_timer() {
+        // 4096 Hz divided by 32 gives 128 calls a second
+        let gbs = Gbs::parse(&rip(0xE0, 0x04)).unwrap();
+        assert!(gbs.uses_timer());
+        assert_eq!(gbs.play_rate(), 128.0);
+        let mut player = GbsPlayer::new(gbs).unwrap();
+        for _ in 0..FRAME_RATE as usize {
+            player.run_frame();
+        }
+        assert!((124..=128).contains(&player.emulator().read_memory(0xC001)));
+    }
+
+    #[test]
+    fn play_on_timer_double_speed() {
+        let gbs = Gbs::parse(&rip(0xE0, 0x84)).unwrap();
+        assert!(gbs.double_speed());
+        assert_eq!(gbs.play_rate(), 256.0);
+        let mut player = GbsPlayer::new(gbs).unwrap();
+        assert_eq!(player.emulator().read_memory(0xFF4D) & 0x80, 0x80);
+        // Half a second, before the call counter wraps
+        for _ in 0..FRAME_RATE as usize / 2 {
+            player.run_frame();
+        }
+        assert!((124..=128).contains(&player.emulator().read_memory(0xC001)));
+    }
+
+    #[test]
+    fn render_fades_out() {
+        let mut player = GbsPlayer::new(Gbs::parse(&rip(0, 0)).unwrap()).unwrap();
+        let mut sink = Samples(vec![]);
+        player.render(0, Duration::from_secs(1), Duration::from_millis(500), &mut sink);
+        assert_eq!(sink.0.len(), 2 * 8000);
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak(&sink.0[..8000]) > 0.05);
+        assert!(peak(&sink.0[2 * 7950..]) < peak(&sink.0[..8000]) / 10.0);
+    }
+}
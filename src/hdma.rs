@@ -0,0 +1,134 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Bytes copied per HBlank, general purpose transfers copy their blocks back to back.
+pub const HDMA_BLOCK: u16 = 0x10;
+/// M-cycles the CPU is held for per block at normal speed.
+pub const HDMA_BLOCK_CYCLES: usize = 8;
+
+/// The CGB VRAM DMA behind HDMA1-HDMA5, which copies from ROM or RAM into the current VRAM bank.
+#[derive(Clone)]
+pub struct Hdma {
+    source: u16,
+    /// Offset into VRAM, the upper three bits are ignored.
+    destination: u16,
+    /// Blocks left minus one, as HDMA5 reads back.
+    length: u8,
+    /// An HBlank transfer is running.
+    active: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Hdma::new()
+    }
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            active: false,
+        }
+    }
+
+    /// HDMA5 as the CPU reads it, bit 7 is clear while an HBlank transfer runs and set once it
+    /// completed or was cancelled. A completed transfer reads 0xFF.
+    pub fn status(&self) -> u8 {
+        (!self.active as u8) << 7 | self.length
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// HDMA1-HDMA4 as last written with their masks applied, and HDMA5 as it reads. The CPU only
+    /// sees 0xFF for the first four, this is for snapshots.
+    pub fn register(&self, address: u16) -> u8 {
+        match address {
+            0xFF51 => (self.source >> 8) as u8,
+            0xFF52 => self.source as u8,
+            0xFF53 => (self.destination >> 8) as u8,
+            0xFF54 => self.destination as u8,
+            _ => self.status(),
+        }
+    }
+
+    /// Writes HDMA1-HDMA4, or puts HDMA5 back as `status` read it without starting anything.
+    pub fn set_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF51 => self.source = self.source & 0x00FF | (value as u16) << 8,
+            0xFF52 => self.source = self.source & 0xFF00 | (value & 0xF0) as u16,
+            0xFF53 => self.destination = self.destination & 0x00FF | ((value & 0x1F) as u16) << 8,
+            0xFF54 => self.destination = self.destination & 0xFF00 | (value & 0xF0) as u16,
+            _ => {
+                self.active = value & 0x80 == 0;
+                self.length = value & 0x7F;
+            }
+        }
+    }
+
+    /// Handles a write to HDMA5 and returns the blocks to copy right away, all of them for a
+    /// general purpose transfer. Clearing bit 7 during an HBlank transfer cancels it instead.
+    pub fn start(&mut self, value: u8) -> u8 {
+        if self.active && value & 0x80 == 0 {
+            self.active = false;
+            return 0;
+        }
+        self.length = value & 0x7F;
+        self.active = value & 0x80 != 0;
+        if self.active { 0 } else { self.length + 1 }
+    }
+
+    /// Source and VRAM address of the next block. Both move on and the transfer ends after the
+    /// last one, leaving HDMA5 at 0xFF.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.destination);
+        self.source = self.source.wrapping_add(HDMA_BLOCK);
+        self.destination = (self.destination + HDMA_BLOCK) & 0x1FF0;
+        self.length = self.length.wrapping_sub(1) & 0x7F;
+        if self.length == 0x7F {
+            self.active = false;
+        }
+        block
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.status());
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.u16()?;
+        self.destination = state.u16()? & 0x1FF0;
+        self.set_register(0xFF55, state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hdma::Hdma;
+
+    #[test]
+    fn registers() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.status(), 0xFF);
+        for (address, value) in [(0xFF51, 0xC1), (0xFF52, 0x2F), (0xFF53, 0xFF), (0xFF54, 0xFF)] {
+            hdma.set_register(address, value);
+        }
+        assert_eq!(hdma.start(0x82), 0);
+        assert_eq!(hdma.status(), 0x02);
+        assert_eq!(hdma.next_block(), (0xC120, 0x9FF0));
+        assert_eq!(hdma.next_block(), (0xC130, 0x8000));
+        assert_eq!(hdma.status(), 0x00);
+
+        // Cancelling keeps the blocks that were left
+        assert_eq!(hdma.start(0x00), 0);
+        assert_eq!(hdma.status(), 0x80);
+        assert_eq!(hdma.start(0x03), 4);
+        assert!(!hdma.active());
+    }
+}
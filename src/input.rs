@@ -1,10 +1,40 @@
 use std::fmt::Debug;
-use gilrs::{Gilrs, Button, Event, GamepadId, Gamepad};
+use gilrs::{Event, EventType, GamepadId, Gilrs};
 use crate::bus::Bus;
-use crate::mbc::MBC;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+}
+
+impl Button {
+    /// Bit of this button in the bus joypad state, the low nibble holds the action buttons
+    /// and the high nibble the d-pad, both in the order of the 0xFF00 input lines.
+    pub fn mask(&self) -> u8 {
+        match self {
+            Button::A => 0x01,
+            Button::B => 0x02,
+            Button::Select => 0x04,
+            Button::Start => 0x08,
+            Button::Right => 0x10,
+            Button::Left => 0x20,
+            Button::Up => 0x40,
+            Button::Down => 0x80,
+        }
+    }
+}
 
 pub trait Input {
     fn check_input(&mut self, _: &mut Bus) {}
+    /// A button key of the output went down or up, `check_input` hands it to the machine.
+    fn key(&mut self, _: Button, _: bool) {}
 }
 
 pub struct Dummy {
@@ -14,49 +44,84 @@ impl Dummy {
         Dummy{}
     }
 }
-impl Input for Dummy {
-    fn check_input(&mut self, bus: &mut Bus) {
-        bus.reset_joypad_buttons();
-        bus.set_int_request_joypad(false);
-    }
-}
+impl Input for Dummy {}
+
+/// Gamepads through gilrs, with the A button on the bottom face button. Each gamepad is a
+/// player in the order they are first used, for games that ask a Super Game Boy for several.
 pub struct Controller {
+    gilrs: Gilrs,
+    players: Vec<GamepadId>,
 }
 impl Controller {
-    pub fn new() -> Self{
-        let girls = Gilrs::new().unwrap();
-        for (id, gamepad) in girls.gamepads() {
-            println!("Gamepad with id {} and name {} is connected",
-                     id, gamepad.name());
-        }
-        Controller {
-        }
+    pub fn new() -> Result<Self, Box<gilrs::Error>> {
+        Ok(Controller {
+            gilrs: Gilrs::new().map_err(Box::new)?,
+            players: vec![],
+        })
+    }
+
+    fn button(button: gilrs::Button) -> Option<Button> {
+        Some(match button {
+            gilrs::Button::South => Button::A,
+            gilrs::Button::East => Button::B,
+            gilrs::Button::Select => Button::Select,
+            gilrs::Button::Start => Button::Start,
+            gilrs::Button::DPadRight => Button::Right,
+            gilrs::Button::DPadLeft => Button::Left,
+            gilrs::Button::DPadUp => Button::Up,
+            gilrs::Button::DPadDown => Button::Down,
+            _ => return None,
+        })
     }
 }
-impl Input for Controller{
+impl Input for Controller {
     fn check_input(&mut self, bus: &mut Bus) {
-        bus.reset_joypad_buttons();
-        bus.set_int_request_joypad(false);
+        while let Some(Event { id, event, .. }) = self.gilrs.next_event() {
+            let (button, pressed) = match event {
+                EventType::ButtonPressed(button, _) => (button, true),
+                EventType::ButtonReleased(button, _) => (button, false),
+                _ => continue,
+            };
+            let Some(button) = Controller::button(button) else {
+                continue;
+            };
+            let player = match self.players.iter().position(|player| *player == id) {
+                Some(player) => player,
+                None => {
+                    self.players.push(id);
+                    self.players.len() - 1
+                }
+            };
+            match pressed {
+                true => bus.press_player(player, button),
+                false => bus.release_player(player, button),
+            }
+        }
     }
 }
 
+/// The keys of the output window, which maps them to buttons.
 pub struct Keyboard {
+    keys: Vec<(Button, bool)>,
 }
 impl Keyboard {
     pub fn new() -> Self{
-        let girls = Gilrs::new().unwrap();
-        for (id, gamepad) in girls.gamepads() {
-            println!("Gamepad with id {} and name {} is connected",
-                     id, gamepad.name());
-        }
         Keyboard {
+            keys: vec![],
         }
     }
 }
 impl Input for Keyboard {
     fn check_input(&mut self, bus: &mut Bus) {
-        bus.reset_joypad_buttons();
-        bus.set_int_request_joypad(false);
+        for (button, pressed) in self.keys.drain(..) {
+            match pressed {
+                true => bus.press(button),
+                false => bus.release(button),
+            }
+        }
     }
-}
 
+    fn key(&mut self, button: Button, pressed: bool) {
+        self.keys.push((button, pressed));
+    }
+}
@@ -0,0 +1,31 @@
+pub mod cpu;
+pub mod bus;
+pub mod emulator;
+mod register;
+pub mod memory;
+pub mod ppu;
+mod fetcher;
+pub mod output;
+pub mod audio;
+mod window_fetcher;
+pub mod input;
+pub mod mbc;
+pub mod error;
+pub mod cartridge;
+pub mod rtc;
+pub mod apu;
+pub mod state;
+pub mod bess;
+pub mod rewind;
+pub mod gbs;
+pub mod model;
+pub mod palette;
+pub mod hdma;
+pub mod compat;
+pub mod sgb;
+
+pub use crate::emulator::Emulator;
+pub use crate::error::LoadError;
+pub use crate::state::StateError;
+pub use crate::input::Button;
+pub use crate::model::Model;
@@ -1,69 +1,360 @@
 use std::fmt::Debug;
+use std::fs;
 use std::io;
-use std::ops::Deref;
-use std::path::Path;
-use clap::Parser;
-use crate::emulator::Emulator;
-use miniquad::*;
-use macroquad::prelude::*;
-use crate::input::Controller;
-
-mod cpu;
-mod bus;
-mod emulator;
-mod register;
-mod memory;
-mod ppu;
-mod fetcher;
-mod output;
-mod window_fetcher;
-mod input;
-mod mbc;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use clap::{Parser, ValueEnum};
+use rusty_gb::input::Input;
+use rusty_gb::audio::AudioSink;
+use rusty_gb::compat::PaletteCombo;
+use rusty_gb::emulator::{MAX_SPEED, MIN_SPEED};
+use rusty_gb::gbs::{Gbs, GbsPlayer};
+use rusty_gb::{audio, bess, input, output, Emulator, LoadError, Model, StateError};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[value(rename_all = "PascalCase")]
+enum OutputKind {
+    Terminal,
+    Dummy,
+    #[value(name = "LCD")]
+    Lcd,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[value(rename_all = "PascalCase")]
+enum InputKind {
+    Dummy,
+    Controller,
+    Keyboard,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[value(rename_all = "UPPERCASE")]
+enum ModelKind {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+}
+
+impl From<ModelKind> for Model {
+    fn from(kind: ModelKind) -> Model {
+        match kind {
+            ModelKind::Dmg0 => Model::Dmg0,
+            ModelKind::Dmg => Model::Dmg,
+            ModelKind::Mgb => Model::Mgb,
+            ModelKind::Sgb => Model::Sgb,
+            ModelKind::Sgb2 => Model::Sgb2,
+            ModelKind::Cgb => Model::Cgb,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[value(rename_all = "kebab-case")]
+enum PaletteKind {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl From<PaletteKind> for PaletteCombo {
+    fn from(kind: PaletteKind) -> PaletteCombo {
+        match kind {
+            PaletteKind::Up => PaletteCombo::Up,
+            PaletteKind::UpA => PaletteCombo::UpA,
+            PaletteKind::UpB => PaletteCombo::UpB,
+            PaletteKind::Left => PaletteCombo::Left,
+            PaletteKind::LeftA => PaletteCombo::LeftA,
+            PaletteKind::LeftB => PaletteCombo::LeftB,
+            PaletteKind::Down => PaletteCombo::Down,
+            PaletteKind::DownA => PaletteCombo::DownA,
+            PaletteKind::DownB => PaletteCombo::DownB,
+            PaletteKind::Right => PaletteCombo::Right,
+            PaletteKind::RightA => PaletteCombo::RightA,
+            PaletteKind::RightB => PaletteCombo::RightB,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Speed {
+    Multiplier(f64),
+    Uncapped,
+}
+
+fn parse_speed(value: &str) -> Result<Speed, String> {
+    if value.eq_ignore_ascii_case("uncapped") {
+        return Ok(Speed::Uncapped);
+    }
+    match value.trim_end_matches(['x', 'X']).parse::<f64>() {
+        Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => Ok(Speed::Multiplier(speed)),
+        Ok(_) => Err(format!("the speed goes from {} to {}", MIN_SPEED, MAX_SPEED)),
+        Err(_) => Err("expected a multiplier like 2 or 0.5, or uncapped".to_string()),
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value = "Terminal", required = false)]
-    output: String,
+    /// Path to the ROM to run
+    rom: PathBuf,
+
+    /// Treat the file as a GBS rip and render its songs to the --wav file
+    #[arg(long)]
+    gbs: bool,
+
+    /// Song of the GBS rip to render counted from 1, all of them when left out
+    #[arg(long)]
+    track: Option<u8>,
+
+    /// Seconds of each rendered song, including the fade
+    #[arg(long, default_value_t = 150.0)]
+    track_length: f64,
 
-    #[arg(short, long, default_value = "Dummy", required = false)]
-    input: String,
+    /// Seconds over which each rendered song fades out
+    #[arg(long, default_value_t = 8.0)]
+    fade: f64,
 
-    #[arg(short, long, default_value_t = 4u8, required = false)]
-    size: u8,
+    #[arg(short, long, value_enum, ignore_case = true, default_value = "Terminal")]
+    output: OutputKind,
+
+    /// Where buttons come from, Keyboard takes the arrow keys, X, Z, Enter and Space of the LCD
+    /// output and Controller the connected gamepads
+    #[arg(short, long, value_enum, ignore_case = true, default_value = "Dummy")]
+    input: InputKind,
+
+    /// Size of a Game Boy pixel on screen
+    #[arg(short, long, alias = "size", default_value_t = 4u8)]
+    scale: u8,
+
+    /// Stop after this many frames, 0 runs until the window is closed
+    #[arg(short, long, default_value_t = 0)]
+    frames: usize,
+
+    /// Run without a window as fast as possible
+    #[arg(long)]
+    headless: bool,
+
+    /// Speed as a multiple of real hardware from 0.25 to 8, or "uncapped". The LCD output
+    /// changes it with =, - and Tab
+    #[arg(long, value_parser = parse_speed)]
+    speed: Option<Speed>,
+
+    /// Hardware to emulate, without a boot ROM the machine starts as its boot ROM left it.
+    /// Defaults to a CGB for cartridges that support one and a DMG otherwise. An SGB adds its
+    /// border and colours for cartridges made for it
+    #[arg(short, long, value_enum, ignore_case = true)]
+    model: Option<ModelKind>,
+
+    /// Path to a boot ROM to run before the cartridge, 256 bytes or 2304 for a CGB
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Colours for a DMG cartridge on a CGB, as picked by holding buttons at the boot logo.
+    /// Defaults to the ones the boot ROM looks up for the title
+    #[arg(long, value_enum, ignore_case = true)]
+    palette: Option<PaletteKind>,
+
+    /// Frames between rewind snapshots, rewinding is held on Backspace in the LCD output
+    #[arg(long, default_value_t = 2)]
+    rewind_interval: usize,
+
+    /// Memory for rewind snapshots in MiB, 0 turns rewinding off
+    #[arg(long, default_value_t = 64)]
+    rewind_memory: usize,
+
+    /// Record the sound to this WAV file
+    #[arg(long)]
+    wav: Option<PathBuf>,
+
+    /// Samples per second of recorded sound
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Save state to start from, native or BESS
+    #[arg(long)]
+    state: Option<PathBuf>,
+
+    /// Write a BESS state to this path on exit, to open the session in other emulators
+    #[arg(long)]
+    export_bess: Option<PathBuf>,
 }
 
 fn main() {
     let args = Args::parse();
-    let output: Box<dyn output::Output> = match args.output.as_str() {
-        "Terminal" => Box::new(output::terminal::Terminal::new(args.size as f64)),
-        "Dummy" => Box::new(output::dummy::Dummy::new()),
-        "LCD" => Box::new(output::lcd::LCD::new(4)),
-        _ => panic!("Unknown output type"),
+    if args.gbs {
+        render_gbs(&args);
+        return;
+    }
+    let output: Box<dyn output::Output> = match (args.headless, args.output) {
+        (true, _) | (_, OutputKind::Dummy) => Box::new(output::dummy::Dummy::new()),
+        (_, OutputKind::Terminal) => Box::new(output::terminal::Terminal::new(args.scale as f64)),
+        (_, OutputKind::Lcd) => Box::new(output::lcd::LCD::new(args.scale as u32)),
+    };
+    match args.input {
+        InputKind::Dummy => run(&args, input::Dummy::new(), output),
+        InputKind::Controller => match input::Controller::new() {
+            Ok(controller) => run(&args, controller, output),
+            Err(error) => {
+                eprintln!("Could not open gamepads: {}", error);
+                process::exit(1);
+            }
+        },
+        InputKind::Keyboard => run(&args, input::Keyboard::new(), output),
+    }
+}
+
+fn run<I: Input>(args: &Args, input: I, output: Box<dyn output::Output>) {
+    let mut emu = match Emulator::from_path(&args.rom, input, output) {
+        Ok(emu) => emu,
+        Err(error) => {
+            eprintln!("Could not load {}: {}", args.rom.display(), error);
+            process::exit(1);
+        }
+    };
+    if let Some(model) = args.model {
+        emu.set_model(model.into());
+    }
+    if let Some(palette) = args.palette {
+        emu.set_compat_palettes(Some(palette.into()));
+    }
+    if let Some(path) = &args.boot_rom {
+        if let Err(error) = fs::read(path).map_err(LoadError::from).and_then(|boot_rom| emu.set_boot_rom(boot_rom)) {
+            eprintln!("Could not load {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    } else if !emu.header().header_checksum_valid() {
+        eprintln!("Warning: header checksum mismatch, real hardware would refuse to boot this cartridge");
+    }
+    if args.headless {
+        emu.set_speed(None);
+    } else {
+        emu.set_rewind(args.rewind_interval, args.rewind_memory << 20);
+    }
+    match args.speed {
+        Some(Speed::Multiplier(speed)) => emu.set_speed(Some(speed)),
+        Some(Speed::Uncapped) => emu.set_speed(None),
+        None => {}
+    }
+
+    if let Some(path) = &args.wav {
+        match audio::wav::Wav::create(path, args.sample_rate) {
+            Ok(wav) => {
+                emu.set_audio_sink(Some(Box::new(wav)));
+            }
+            Err(error) => {
+                eprintln!("Could not create {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.state {
+        let result = fs::read(path).map_err(StateError::from).and_then(|data| match bess::is_bess(&data) {
+            true => emu.import_bess(&data),
+            false => emu.load_state(&data),
+        });
+        if let Err(error) = result {
+            eprintln!("Could not load {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    }
+
+    emu.run(args.frames, &mut io::stdout());
+    if let Err(error) = emu.flush_save() {
+        eprintln!("Could not save cartridge RAM: {}", error);
+    }
+
+    if let Some(path) = &args.export_bess {
+        if let Err(error) = fs::write(path, emu.export_bess()) {
+            eprintln!("Could not write {}: {}", path.display(), error);
+        }
+    }
+}
+
+fn render_gbs(args: &Args) {
+    let gbs = match fs::read(&args.rom).map_err(LoadError::from).and_then(|data| Gbs::parse(&data)) {
+        Ok(gbs) => gbs,
+        Err(error) => {
+            eprintln!("Could not load {}: {}", args.rom.display(), error);
+            process::exit(1);
+        }
     };
-    let input = input::Dummy::new();
-    let mut emu = Emulator::new(
-        Path::new("test-roms").join("Pokemon Red.gb").to_str().unwrap(),
-        input,
-        output,
-    );
-
-    emu.run(60*200, &mut io::stdout());
+    let Some(wav) = &args.wav else {
+        eprintln!("GBS songs are rendered to a file, pass one with --wav");
+        process::exit(1);
+    };
+    let songs: Vec<u8> = match args.track {
+        Some(track) if (1..=gbs.songs).contains(&track) => vec![track - 1],
+        Some(track) => {
+            eprintln!("There is no song {}, {} has {}", track, args.rom.display(), gbs.songs);
+            process::exit(1);
+        }
+        None => (0..gbs.songs).collect(),
+    };
+    let credits: Vec<&str> = [&gbs.title, &gbs.author, &gbs.copyright].into_iter().map(String::as_str).filter(|field| !field.is_empty()).collect();
+    println!("{}, {} songs", credits.join(", "), gbs.songs);
+
+    let mut player = match GbsPlayer::new(gbs) {
+        Ok(player) => player,
+        Err(error) => {
+            eprintln!("Could not load {}: {}", args.rom.display(), error);
+            process::exit(1);
+        }
+    };
+    let length = Duration::from_secs_f64(args.track_length.max(0.0));
+    let fade = Duration::from_secs_f64(args.fade.max(0.0));
+    for &song in &songs {
+        // One file per song when rendering several, numbered like the songs
+        let path = match songs.len() {
+            1 => wav.clone(),
+            _ => {
+                let stem = wav.file_stem().unwrap_or_default().to_string_lossy();
+                let extension = wav.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+                wav.with_file_name(format!("{}-{:02}{}", stem, song + 1, extension))
+            }
+        };
+        let result = audio::wav::Wav::create(&path, args.sample_rate).and_then(|mut sink| {
+            player.render(song, length, fade, &mut sink);
+            sink.finish()
+        });
+        match result {
+            Ok(()) => println!("Song {} written to {}", song + 1, path.display()),
+            Err(error) => {
+                eprintln!("Could not write {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
-    use crate::emulator::Emulator;
-    use crate::input;
-    use crate::output::dummy::Dummy;
+    use rusty_gb::Emulator;
+    use rusty_gb::input;
+    use rusty_gb::output::dummy::Dummy;
 
     #[test]
     fn blargg1() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("01-special.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("01-special.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -71,10 +362,11 @@ mod tests {
     }
     #[test]
     fn blargg2() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("02-interrupts.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("02-interrupts.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -82,10 +374,11 @@ mod tests {
     }
     #[test]
     fn blargg3() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("03-op sp,hl.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("03-op sp,hl.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -93,10 +386,11 @@ mod tests {
     }
     #[test]
     fn blargg4() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("04-op r,imm.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("04-op r,imm.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -104,10 +398,11 @@ mod tests {
     }
     #[test]
     fn blargg5() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("05-op rp.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("05-op rp.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -115,10 +410,11 @@ mod tests {
     }
     #[test]
     fn blargg6() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("06-ld r,r.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("06-ld r,r.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -126,10 +422,11 @@ mod tests {
     }
     #[test]
     fn blargg7() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("07-jr,jp,call,ret,rst.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("07-jr,jp,call,ret,rst.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -137,10 +434,11 @@ mod tests {
     }
     #[test]
     fn blargg8() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("08-misc instrs.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("08-misc instrs.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -148,10 +446,11 @@ mod tests {
     }
     #[test]
     fn blargg9() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("09-op r,r.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("09-op r,r.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -159,10 +458,11 @@ mod tests {
     }
     #[test]
     fn blargg10() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("10-bit ops.gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("10-bit ops.gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
@@ -170,13 +470,88 @@ mod tests {
     }
     #[test]
     fn blargg11() {
-        let mut emu = Emulator::new(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("11-op a,(hl).gb").to_str().unwrap(), input::Dummy::new(), Box::new(Dummy::new()));
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("cpu_instrs").join("individual").join("11-op a,(hl).gb"), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
         let mut stdout = Vec::new();
+        emu.set_speed(None);
 
-        emu.run(600, &mut stdout);
+        emu.run(1500, &mut stdout);
 
         let output = String::from_utf8_lossy(&*stdout);
         assert_eq!(output.contains("Passed"), true);
         assert_eq!(output.contains("Failed"), false);
     }
+
+    /// Runs one of blargg's dmg_sound singles, which report on the serial port like cpu_instrs.
+    /// The ROMs are not checked in, they go under test-roms/gb-test-roms-master/dmg_sound.
+    fn dmg_sound(name: &str) {
+        let mut emu = Emulator::from_path(Path::new("test-roms").join("gb-test-roms-master").join("dmg_sound").join("rom_singles").join(name), input::Dummy::new(), Box::new(Dummy::new())).unwrap();
+        let mut stdout = Vec::new();
+        emu.set_speed(None);
+
+        emu.run(1500, &mut stdout);
+
+        let output = String::from_utf8_lossy(&stdout);
+        assert!(output.contains("Passed"));
+        assert!(!output.contains("Failed"));
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound01() {
+        dmg_sound("01-registers.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound02() {
+        dmg_sound("02-len ctr.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound03() {
+        dmg_sound("03-trigger.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound04() {
+        dmg_sound("04-sweep.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound05() {
+        dmg_sound("05-sweep details.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound06() {
+        dmg_sound("06-overflow on trigger.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound07() {
+        dmg_sound("07-len sweep period sync.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound08() {
+        dmg_sound("08-len ctr during power.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound09() {
+        dmg_sound("09-wave read while on.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound10() {
+        dmg_sound("10-wave trigger while on.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound11() {
+        dmg_sound("11-regs after power.gb");
+    }
+    #[test]
+    #[ignore = "needs the dmg_sound ROMs in test-roms"]
+    fn dmg_sound12() {
+        dmg_sound("12-wave write while on.gb");
+    }
 }
\ No newline at end of file
@@ -1,14 +1,47 @@
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use bitfield::Bit;
-use bytesize::{kb, kib, ByteSize};
-use crate::bus::ROM_N_SIZE;
+use crate::bus::{ERAM, ROM_N_SIZE};
+use crate::cartridge::NINTENDO_LOGO;
 use crate::memory::Memory;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::rtc::{Rtc, RtcClock, RTC_DAYS_HIGH, RTC_SECONDS};
 
-pub trait MBC {
+pub trait MBC: MBCClone {
     fn write(&mut self, address: u16, value: u8, memory: &mut Memory) {}
     fn read(&self, address: u16, memory: &Memory) -> u8 { memory.get(address) }
+    fn rumble(&self) -> bool { false }
+    /// Advances anything on the cartridge that runs on its own, by a number of T-cycles.
+    fn tick(&mut self, _: usize) {}
+    fn rtc(&mut self) -> Option<&mut Rtc> { None }
+    /// Writes that bring a freshly loaded cartridge into the current banking state.
+    fn registers(&self, _: &Memory) -> Vec<(u16, u8)> { vec![] }
+    /// Registers that live on the cartridge rather than in `Memory`.
+    fn save_state(&self, _: &mut StateWriter) {}
+    fn load_state(&mut self, _: &mut StateReader) -> Result<(), StateError> { Ok(()) }
 }
 
+/// Lets a boxed MBC be cloned along with the rest of the machine.
+pub trait MBCClone {
+    fn clone_box(&self) -> Box<dyn MBC>;
+}
+impl<T: MBC + Clone + 'static> MBCClone for T {
+    fn clone_box(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
+}
+impl Clone for Box<dyn MBC> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+fn ram_enable(memory: &Memory) -> u8 {
+    match memory.eram_enable {
+        true => 0x0A,
+        false => 0x00,
+    }
+}
+
+#[derive(Clone)]
 pub struct MBC0 {
 
 }
@@ -34,51 +67,134 @@ impl MBC for MBC0 {
     }
 }
 
+#[derive(Clone)]
 pub struct MBC2 {}
 impl MBC2 {
     pub fn new() -> Self {
         MBC2 {}
     }
 }
+#[derive(Clone)]
 pub struct MBC1 {
-    banking_mode: bool
+    bank1: u8,
+    bank2: u8,
+    banking_mode: bool,
+    multicart: bool,
 }
 impl MBC1 {
-    pub fn new() -> Self {
-        MBC1 { banking_mode: false }
+    pub fn new(multicart: bool) -> Self {
+        MBC1 { bank1: 1, bank2: 0, banking_mode: false, multicart }
+    }
+    /// MBC1M collections are 1 MiB carts holding 256 KiB games, each starting with its own boot logo.
+    pub fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 64 * ROM_N_SIZE as usize {
+            return false;
+        }
+        (1..4).any(|game| rom[game * 0x40000 + 0x0104..game * 0x40000 + 0x0134] == NINTENDO_LOGO)
+    }
+    /// On MBC1M BANK2 is wired one line lower, leaving 4 bits of BANK1 for the bank within a game.
+    fn bank2_shift(&self) -> u8 {
+        match self.multicart {
+            true => 4,
+            false => 5,
+        }
+    }
+    fn update(&self, memory: &mut Memory) {
+        let bank1 = match self.multicart {
+            true => self.bank1 & 0x0F,
+            false => self.bank1,
+        };
+        memory.current_rom = (self.bank2 as u16) << self.bank2_shift() | bank1 as u16;
+        memory.rom_address_cache = (memory.current_rom as usize).wrapping_sub(1).wrapping_mul(ROM_N_SIZE as usize);
+        memory.current_eram = match self.banking_mode {
+            true => self.bank2 as u16,
+            false => 0,
+        };
     }
 }
 impl MBC for MBC1 {
+    fn registers(&self, memory: &Memory) -> Vec<(u16, u8)> {
+        vec![(0x0000, ram_enable(memory)), (0x2000, self.bank1), (0x4000, self.bank2), (0x6000, self.banking_mode as u8)]
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.bank1);
+        state.u8(self.bank2);
+        state.bool(self.banking_mode);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.bank1 = state.u8()?;
+        self.bank2 = state.u8()?;
+        self.banking_mode = state.bool()?;
+        Ok(())
+    }
+    fn read(&self, address: u16, memory: &Memory) -> u8 {
+        match address {
+            ..=0x3FFF => {
+                if self.banking_mode {
+                    let bank = (self.bank2 as usize) << self.bank2_shift();
+                    memory.rom[(bank * ROM_N_SIZE as usize + address as usize) & (memory.rom.len() - 1)]
+                } else {
+                    memory.get(address)
+                }
+            }
+            0xA000..=0xBFFF => {
+                if memory.eram_enable {
+                    memory.get(address)
+                } else {
+                    0xFF
+                }
+            }
+            _ => memory.get(address)
+        }
+    }
     fn write(&mut self, address: u16, value: u8, memory: &mut Memory) {
         match address {
             ..=0x1FFF => {
                 memory.eram_enable = 0x0A == (value & 0x0F)
             },
             0x2000..=0x3FFF => {
-                memory.current_rom = (value as u16 & 0b11111) | memory.current_rom & 0b01100000;
-                if memory.current_rom & 0b11111 == 0 {
-                    memory.current_rom = 1;
+                self.bank1 = value & 0b11111;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
                 }
-                memory.rom_address_cache = (memory.current_rom as usize - 1) * ROM_N_SIZE as usize;
+                self.update(memory);
             },
             0x4000..=0x5FFF => {
-                if memory.eram.len() >= ByteSize::kib(16).as_u64() as usize {
-                    memory.current_eram = (value & 0b11) as u16;
-                } else if memory.rom.len() >= ByteSize::mib(1).as_u64() as usize {
-                    memory.current_rom = (value as u16 & 0b01100000) | memory.current_rom & 0b11111;
-                }
-                memory.rom_address_cache = (memory.current_rom as usize - 1) * ROM_N_SIZE as usize;
+                self.bank2 = value & 0b11;
+                self.update(memory);
             },
             0x6000..=0x7FFF => {
                 self.banking_mode = value & 0x1 == 1;
+                self.update(memory);
             },
+            0xA000..=0xBFFF => {
+                if memory.eram_enable {
+                    memory.set(address, value)
+                }
+            }
             _ => {
-                memory.set(address, value);
+                panic!("Not implemented for MBC1! {:#04x}", address)
             }
         }
     }
 }
 impl MBC for MBC2 {
+    fn registers(&self, memory: &Memory) -> Vec<(u16, u8)> {
+        vec![(0x0000, ram_enable(memory)), (0x0100, memory.current_rom as u8)]
+    }
+    fn read(&self, address: u16, memory: &Memory) -> u8 {
+        match address {
+            // Only the lower nibble is wired up, the rest of the data bus floats high
+            0xA000..=0xBFFF => {
+                if memory.eram_enable {
+                    0xF0 | memory.get(ERAM + (address & 0x1FF))
+                } else {
+                    0xFF
+                }
+            }
+            _ => memory.get(address)
+        }
+    }
     fn write(&mut self, address: u16, value: u8, memory: &mut Memory) {
         match address {
             ..=0x3FFF => {
@@ -92,51 +208,43 @@ impl MBC for MBC2 {
                     memory.rom_address_cache = (memory.current_rom as usize - 1) * ROM_N_SIZE as usize;
                 }
             },
+            0x4000..=0x7FFF => {},
+            0xA000..=0xBFFF => {
+                if memory.eram_enable {
+                    memory.set(ERAM + (address & 0x1FF), value & 0x0F)
+                }
+            }
             _ => {
-                panic!("Not implemented for MBC1!")
+                panic!("Not implemented for MBC2! {:#04x}", address)
             }
         }
     }
 }
 
+#[derive(Clone)]
 pub struct MBC3 {
-    rtc_registers: bool,
-    rtc_register: u8
+    rtc: Option<Rtc>,
+    rtc_register: Option<u8>,
 }
 impl MBC3 {
-    pub fn new() -> Self {
-        MBC3 { rtc_registers: false, rtc_register: 0x08 }
+    pub fn new(has_rtc: bool) -> Self {
+        MBC3 { rtc: has_rtc.then(|| Rtc::new(RtcClock::WallClock)), rtc_register: None }
     }
 }
 impl MBC for MBC3 {
+    fn registers(&self, memory: &Memory) -> Vec<(u16, u8)> {
+        let bank = self.rtc_register.unwrap_or(memory.current_eram as u8);
+        vec![(0x0000, ram_enable(memory)), (0x2000, memory.current_rom as u8), (0x4000, bank)]
+    }
     fn read(&self, address: u16, memory: &Memory) -> u8 {
         match address {
             0xA000..=0xBFFF => {
-                if memory.eram_enable {
-                    memory.get(address)
-                } else {
-                    match self.rtc_register { 
-                        0x08 => {
-                            (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() % 60) as u8
-                        }
-                        0x09 => {
-                            ((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60) % 60) as u8
-                        }
-                        0x0A => {
-                            ((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600) % 24) as u8
-                        }
-                        0x0B => {
-                            (((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 / 24) % 512) & 0xFF) as u8
-                        }
-                        0x0C => {
-                            (((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 / 24) % 512) & 0x100) as u8
-                        }
-                        _ => {
-                            panic!("no {:#02x}", self.rtc_register)
-                        }
-                    }
+                match (memory.eram_enable, self.rtc_register, &self.rtc) {
+                    (false, _, _) => 0xFF,
+                    (true, Some(register), Some(rtc)) => rtc.read(register),
+                    (true, Some(_), None) => 0xFF,
+                    (true, None, _) => memory.get(address),
                 }
-
             }
             _ => {
                 memory.get(address)
@@ -147,7 +255,6 @@ impl MBC for MBC3 {
         match address {
             ..=0x1FFF => {
                 memory.eram_enable = 0x0A == (value & 0x0F);
-                self.rtc_registers = 0x0A == (value & 0x0F)
             },
             0x2000..=0x3FFF => {
                 memory.current_rom = (value & 0b1111111) as u16;
@@ -157,27 +264,305 @@ impl MBC for MBC3 {
                 memory.rom_address_cache = (memory.current_rom as usize - 1) * ROM_N_SIZE as usize;
             },
             0x4000..=0x5FFF => {
-                if value <= 0x03 {
-                    memory.current_eram = (value & 0b11) as u16;
-                    memory.eram_enable = true;
-                } else if value <= 0x0c && value >= 0x08 {
-                    memory.eram_enable = false;
-                    self.rtc_register = value
+                if value <= 0x07 {
+                    memory.current_eram = value as u16;
+                    self.rtc_register = None;
+                } else if (RTC_SECONDS..=RTC_DAYS_HIGH).contains(&value) {
+                    self.rtc_register = Some(value);
                 }
             },
             0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            },
+            0xA000..=0xBFFF => {
+                match (memory.eram_enable, self.rtc_register, &mut self.rtc) {
+                    (false, _, _) => {},
+                    (true, Some(register), Some(rtc)) => rtc.write(register, value),
+                    (true, Some(_), None) => {},
+                    (true, None, _) => memory.set(address, value),
+                }
+            }
+            _ => {
+                panic!("Not implemented for MBC3! {:#04x}", address)
+            }
+        }
+    }
+    fn tick(&mut self, cycles: usize) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.rtc_register.unwrap_or(0));
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rtc_register = Some(state.u8()?).filter(|register| *register != 0);
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+}
 
+#[derive(Clone)]
+pub struct MBC5 {
+    has_rumble: bool,
+    rumble: bool,
+}
+impl MBC5 {
+    pub fn new(has_rumble: bool) -> Self {
+        MBC5 { has_rumble, rumble: false }
+    }
+}
+impl MBC for MBC5 {
+    fn registers(&self, memory: &Memory) -> Vec<(u16, u8)> {
+        let bank = memory.current_eram as u8 | (self.rumble as u8) << 3;
+        vec![(0x0000, ram_enable(memory)), (0x2000, memory.current_rom as u8), (0x3000, (memory.current_rom >> 8) as u8), (0x4000, bank)]
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.rumble);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rumble = state.bool()? && self.has_rumble;
+        Ok(())
+    }
+    fn read(&self, address: u16, memory: &Memory) -> u8 {
+        match address {
+            0xA000..=0xBFFF => {
+                if memory.eram_enable {
+                    memory.get(address)
+                } else {
+                    0xFF
+                }
+            }
+            _ => memory.get(address)
+        }
+    }
+    fn write(&mut self, address: u16, value: u8, memory: &mut Memory) {
+        match address {
+            ..=0x1FFF => {
+                memory.eram_enable = 0x0A == (value & 0x0F)
+            },
+            0x2000..=0x2FFF => {
+                memory.current_rom = (memory.current_rom & 0x100) | value as u16;
+                // Unlike the older MBCs bank 0 can be mapped to 0x4000 as well, hence the wrapping
+                memory.rom_address_cache = (memory.current_rom as usize).wrapping_sub(1).wrapping_mul(ROM_N_SIZE as usize);
+            },
+            0x3000..=0x3FFF => {
+                memory.current_rom = (memory.current_rom & 0xFF) | ((value as u16 & 0x1) << 8);
+                memory.rom_address_cache = (memory.current_rom as usize).wrapping_sub(1).wrapping_mul(ROM_N_SIZE as usize);
             },
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = value.bit(3);
+                    memory.current_eram = (value & 0b111) as u16;
+                } else {
+                    memory.current_eram = (value & 0b1111) as u16;
+                }
+            },
+            0x6000..=0x7FFF => {},
             0xA000..=0xBFFF => {
                 if memory.eram_enable {
                     memory.set(address, value)
-                } else {
-
                 }
             }
             _ => {
-                panic!("Not implemented for MBC3! {:#04x}", address)
+                panic!("Not implemented for MBC5! {:#04x}", address)
             }
         }
     }
-}
\ No newline at end of file
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::cartridge::{Header, NINTENDO_LOGO};
+    use crate::mbc::{MBC, MBC1, MBC2, MBC3, MBC5};
+    use crate::rtc::{RtcClock, RTC_HOURS};
+    use crate::memory::Memory;
+
+    fn memory(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Memory {
+        let banks = 2 << rom_size;
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+            rom[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+        let header = Header::parse(&rom).unwrap();
+        let mut memory = Memory::new();
+        memory.load_rom(rom, &header).unwrap();
+        memory
+    }
+
+    #[test]
+    fn mbc1_rom_banks() {
+        let mut memory = memory(0x01, 0x06, 0x00);
+        let mut mbc = MBC1::new(false);
+        assert_eq!(mbc.read(0x4000, &memory), 1);
+
+        mbc.write(0x2000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 1);
+        mbc.write(0x2000, 0x20, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 1);
+
+        mbc.write(0x2000, 0x12, &mut memory);
+        mbc.write(0x4000, 0x03, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0x72);
+        assert_eq!(mbc.read(0x0000, &memory), 0x00);
+
+        mbc.write(0x6000, 0x01, &mut memory);
+        assert_eq!(mbc.read(0x0000, &memory), 0x60);
+        assert_eq!(mbc.read(0x4000, &memory), 0x72);
+    }
+
+    #[test]
+    fn mbc1_ram_banks() {
+        let mut memory = memory(0x03, 0x00, 0x03);
+        let mut mbc = MBC1::new(false);
+        mbc.write(0xA000, 0x12, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xFF);
+
+        mbc.write(0x0000, 0x0A, &mut memory);
+        mbc.write(0xA000, 0x12, &mut memory);
+        mbc.write(0x4000, 0x02, &mut memory);
+        mbc.write(0xA000, 0x34, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x34);
+
+        mbc.write(0x6000, 0x01, &mut memory);
+        mbc.write(0xA000, 0x56, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x56);
+        mbc.write(0x6000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x34);
+
+        mbc.write(0x0000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_multicart() {
+        let mut memory = memory(0x01, 0x05, 0x00);
+        assert!(!MBC1::is_multicart(&memory.rom));
+        let rom = Arc::get_mut(&mut memory.rom).unwrap();
+        for game in 0..4 {
+            rom[game * 0x40000 + 0x0104..game * 0x40000 + 0x0134].copy_from_slice(&NINTENDO_LOGO);
+        }
+        assert!(MBC1::is_multicart(&memory.rom));
+
+        let mut mbc = MBC1::new(true);
+        mbc.write(0x2000, 0x12, &mut memory);
+        mbc.write(0x4000, 0x03, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0x32);
+
+        mbc.write(0x2000, 0x10, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0x30);
+
+        mbc.write(0x6000, 0x01, &mut memory);
+        assert_eq!(mbc.read(0x0000, &memory), 0x30);
+    }
+
+    #[test]
+    fn mbc2_ram() {
+        let mut memory = memory(0x06, 0x03, 0x00);
+        assert_eq!(memory.eram.len(), 512);
+        let mut mbc = MBC2::new();
+        mbc.write(0xA000, 0x05, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xFF);
+
+        mbc.write(0x0000, 0x0A, &mut memory);
+        mbc.write(0xA000, 0x35, &mut memory);
+        mbc.write(0xA1FF, 0x0C, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xF5);
+        assert_eq!(mbc.read(0xA200, &memory), 0xF5);
+        assert_eq!(mbc.read(0xBFFF, &memory), 0xFC);
+
+        mbc.write(0x0100, 0x03, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0x03);
+        assert_eq!(mbc.read(0xA000, &memory), 0xF5);
+        mbc.write(0x0000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rtc() {
+        let mut memory = memory(0x10, 0x00, 0x03);
+        let mut mbc = MBC3::new(true);
+        mbc.rtc().unwrap().set_clock(RtcClock::Emulated);
+        mbc.write(0x0000, 0x0A, &mut memory);
+        mbc.write(0xA000, 0x12, &mut memory);
+
+        mbc.write(0x4000, RTC_HOURS, &mut memory);
+        mbc.write(0xA000, 0x05, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x05);
+        mbc.tick(3600 * 4194304);
+        assert_eq!(mbc.read(0xA000, &memory), 0x05);
+        mbc.write(0x6000, 0x00, &mut memory);
+        mbc.write(0x6000, 0x01, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x06);
+
+        mbc.write(0x4000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0x12);
+    }
+
+    #[test]
+    fn mbc5_rom_banks() {
+        let mut memory = memory(0x19, 0x08, 0x00);
+        let mut mbc = MBC5::new(false);
+        assert_eq!(mbc.read(0x4000, &memory), 1);
+
+        mbc.write(0x2000, 0x00, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0);
+        assert_eq!(mbc.read(0x4001, &memory), 0);
+
+        mbc.write(0x2000, 0x34, &mut memory);
+        mbc.write(0x3000, 0x01, &mut memory);
+        assert_eq!(mbc.read(0x4000, &memory), 0x34);
+        assert_eq!(mbc.read(0x4001, &memory), 0x01);
+        assert_eq!(mbc.read(0x0000, &memory), 0x00);
+    }
+
+    #[test]
+    fn mbc5_ram_banks() {
+        let mut memory = memory(0x1B, 0x00, 0x04);
+        let mut mbc = MBC5::new(false);
+        mbc.write(0xA000, 0x12, &mut memory);
+        assert_eq!(mbc.read(0xA000, &memory), 0xFF);
+
+        mbc.write(0x0000, 0x0A, &mut memory);
+        for bank in 0..16 {
+            mbc.write(0x4000, bank, &mut memory);
+            mbc.write(0xA000, bank + 0x10, &mut memory);
+        }
+        for bank in 0..16 {
+            mbc.write(0x4000, bank, &mut memory);
+            assert_eq!(mbc.read(0xA000, &memory), bank + 0x10);
+        }
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn mbc5_rumble() {
+        let mut memory = memory(0x1E, 0x00, 0x03);
+        let mut mbc = MBC5::new(true);
+        mbc.write(0x0000, 0x0A, &mut memory);
+        mbc.write(0x4000, 0x09, &mut memory);
+        assert!(mbc.rumble());
+        mbc.write(0xA000, 0x42, &mut memory);
+        mbc.write(0x4000, 0x01, &mut memory);
+        assert!(!mbc.rumble());
+        assert_eq!(mbc.read(0xA000, &memory), 0x42);
+    }
+}
@@ -1,14 +1,20 @@
 use std::ptr::null_mut;
+use std::sync::Arc;
+use crate::cartridge::Header;
+use crate::error::LoadError;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::bus::{ERAM, ERAM_END, ERAM_SIZE, HRAM, HRAM_END, HRAM_SIZE, INT_ENABLE, INT_ENABLE_END, INT_ENABLE_SIZE, IO_REGISTERS, IO_REGISTERS_END, IO_REGISTERS_SIZE, OAM, OAM_END, OAM_SIZE, ROM_0, ROM_0_END, ROM_0_SIZE, ROM_N, ROM_N_END, ROM_N_SIZE, VRAM, VRAM_END, VRAM_SIZE, WRAM_0, WRAM_0_END, WRAM_0_SIZE, WRAM_N, WRAM_N_END, WRAM_N_SIZE};
 
+#[derive(Clone)]
 pub struct Memory {
-    pub(crate) rom: Vec<u8>,
-    vram: Vec<u8>,
+    /// Shared between forks of a machine, nothing writes to it after loading.
+    pub(crate) rom: Arc<[u8]>,
+    pub(crate) vram: Vec<u8>,
     pub(crate) eram: Vec<u8>,
-    wram: Vec<u8>,
-    oam: Vec<u8>,
+    pub(crate) wram: Vec<u8>,
+    pub(crate) oam: Vec<u8>,
     io_registers: Vec<u8>,
-    hram: Vec<u8>,
+    pub(crate) hram: Vec<u8>,
     int_enable: Vec<u8>,
     extra_rom: Vec<Vec<u8>>,
     pub(crate) current_rom: u16,
@@ -16,19 +22,20 @@ pub struct Memory {
     pub(crate) current_wram: u16,
     pub(crate) current_eram: u16,
     pub eram_enable: bool,
+    pub(crate) eram_dirty: bool,
     pub(crate) banking_mode: u8,
     pub(crate) rom_address_cache: usize
 }
 impl Memory {
     pub fn new() -> Memory {
-        Memory { rom: vec![0; (ROM_0_SIZE + ROM_N_SIZE) as usize], vram: vec![0; 2 * VRAM_SIZE as usize], eram: vec![0; ERAM_SIZE as usize], wram: vec![0; 8 * WRAM_0_SIZE as usize], oam: vec![0; OAM_SIZE as usize], io_registers: vec![0; IO_REGISTERS_SIZE as usize], hram: vec![0; HRAM_SIZE as usize], int_enable: vec![0; INT_ENABLE_SIZE as usize], extra_rom: vec![], current_rom: 0, current_eram: 0, current_wram: 1, current_vram: 0, banking_mode: 0, eram_enable: false, rom_address_cache: 0 }
+        Memory { rom: vec![0; (ROM_0_SIZE + ROM_N_SIZE) as usize].into(), vram: vec![0; 2 * VRAM_SIZE as usize], eram: vec![0; ERAM_SIZE as usize], wram: vec![0; 8 * WRAM_0_SIZE as usize], oam: vec![0; OAM_SIZE as usize], io_registers: vec![0; IO_REGISTERS_SIZE as usize], hram: vec![0; HRAM_SIZE as usize], int_enable: vec![0; INT_ENABLE_SIZE as usize], extra_rom: vec![], current_rom: 0, current_eram: 0, current_wram: 1, current_vram: 0, banking_mode: 0, eram_enable: false, eram_dirty: false, rom_address_cache: 0 }
     }
     pub fn get(&self, address: u16) -> u8 {
         match address {
             ..=ROM_0_END => self.rom[address as usize],
-            ROM_N..=ROM_N_END => self.rom[self.rom_address_cache + address as usize],
+            ROM_N..=ROM_N_END => self.rom[self.rom_address_cache.wrapping_add(address as usize) & (self.rom.len() - 1)],
             VRAM..=VRAM_END => self.vram[(self.current_vram * VRAM_SIZE + (address - VRAM)) as usize],
-            ERAM..=ERAM_END => self.eram[(self.current_eram * ERAM_SIZE + (address - ERAM)) as usize],
+            ERAM..=ERAM_END => self.eram[(self.current_eram as usize * ERAM_SIZE as usize + (address - ERAM) as usize) % self.eram.len()],
             WRAM_0..=WRAM_0_END => self.wram[(address - WRAM_0) as usize],
             WRAM_N..=WRAM_N_END => self.wram[(self.current_wram * WRAM_N_SIZE + (address - WRAM_0)) as usize],
             OAM..=OAM_END => self.oam[(address - OAM) as usize],
@@ -43,7 +50,11 @@ impl Memory {
             ..=ROM_0_END => panic!("Read only memory, bug in MBC? {:#04x}", address),
             ROM_N..=ROM_N_END => panic!("Read only memory, bug in MBC? {:#04x}", address),
             VRAM..=VRAM_END => &mut self.vram[(self.current_vram * VRAM_SIZE + (address - VRAM)) as usize],
-            ERAM..=ERAM_END => &mut self.eram[(self.current_eram * ERAM_SIZE + (address - ERAM)) as usize],
+            ERAM..=ERAM_END => {
+                self.eram_dirty = true;
+                let len = self.eram.len();
+                &mut self.eram[(self.current_eram as usize * ERAM_SIZE as usize + (address - ERAM) as usize) % len]
+            },
             WRAM_0..=WRAM_0_END => &mut self.wram[(address - WRAM_0) as usize],
             WRAM_N..=WRAM_N_END => &mut self.wram[(self.current_wram * WRAM_N_SIZE + (address - WRAM_0)) as usize],
             OAM..=OAM_END => &mut self.oam[(address - OAM) as usize],
@@ -54,63 +65,46 @@ impl Memory {
         };
         *target = value
     }
-    pub fn load_rom(&mut self, mut buffer: Vec<u8>) {
-        self.rom[..=ROM_0_END as usize].copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-        self.current_rom = 1;
-
-        match self.get(0x0149) {
-            0x00 => {},
-            0x02 => {
-                self.eram.resize(1 * ERAM_SIZE as usize, 0);
-                self.current_eram = 0;
-            },
-            0x03 => {
-                self.eram.resize(4 * ERAM_SIZE as usize, 0);
-                self.current_eram = 0;
-            },
-            _ => panic!("Not implemented yet! {}", self.get(0x0149))
+    /// Everything but the ROM, which is checked separately when a state is loaded.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for bank in [&self.vram, &self.eram, &self.wram, &self.oam, &self.io_registers, &self.hram, &self.int_enable] {
+            state.bytes(bank);
         }
-        match self.get(0x0148) {
-            0x00 => {
-                self.rom.resize(2 * ROM_N_SIZE as usize, 0);
-                let start = ROM_N_SIZE as usize;
-                let end = start + ROM_N_SIZE as usize;
-                self.rom.get_mut(start..end).unwrap().copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-
-            },
-            0x01 => {
-                self.rom.resize(4 * ROM_N_SIZE as usize, 0);
-                for i in 1..4 {
-                    let start = i * ROM_N_SIZE as usize;
-                    let end = start + ROM_N_SIZE as usize;
-                    self.rom.get_mut(start..end).unwrap().copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-                }
-            },
-            0x03 => {
-                self.rom.resize(16 * ROM_N_SIZE as usize, 0);
-                for i in 1..16 {
-                    let start = i * ROM_N_SIZE as usize;
-                    let end = start + ROM_N_SIZE as usize;
-                    self.rom.get_mut(start..end).unwrap().copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-                }
-            },
-            0x04 => {
-                self.rom.resize(32 * ROM_N_SIZE as usize, 0);
-                for i in 1..32 {
-                    let start = i * ROM_N_SIZE as usize;
-                    let end = start + ROM_N_SIZE as usize;
-                    self.rom.get_mut(start..end).unwrap().copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-                }
-            },
-            0x05 => {
-                self.rom.resize(64 * ROM_N_SIZE as usize, 0);
-                for i in 1..64 {
-                    let start = i * ROM_N_SIZE as usize;
-                    let end = start + ROM_N_SIZE as usize;
-                    self.rom.get_mut(start..end).unwrap().copy_from_slice(&buffer.drain(..ROM_N_SIZE as usize).as_slice());
-                }
-            }
-            _ => panic!("Not implemented yet! {}", self.get(0x0148))
+        state.u16(self.current_rom);
+        state.u16(self.current_vram);
+        state.u16(self.current_wram);
+        state.u16(self.current_eram);
+        state.bool(self.eram_enable);
+        state.u8(self.banking_mode);
+        state.usize(self.rom_address_cache);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for bank in [&mut self.vram, &mut self.eram, &mut self.wram, &mut self.oam, &mut self.io_registers, &mut self.hram, &mut self.int_enable] {
+            state.bytes_into(bank)?;
+        }
+        self.current_rom = state.u16()?;
+        self.current_vram = state.u16()?;
+        self.current_wram = state.u16()?;
+        self.current_eram = state.u16()?;
+        self.eram_enable = state.bool()?;
+        self.banking_mode = state.u8()?;
+        self.rom_address_cache = state.usize()?;
+        Ok(())
+    }
+    pub fn load_rom(&mut self, mut buffer: Vec<u8>, header: &Header) -> Result<(), LoadError> {
+        let ram_bytes = header.ram_bytes()?;
+        if ram_bytes > 0 {
+            self.eram = vec![0; ram_bytes];
+            self.current_eram = 0;
         }
+
+        // Unconnected address lines make a small chip show up mirrored across the whole range,
+        // so pad the file to a power of two and repeat it up to the size the header declares
+        let size = buffer.len().next_power_of_two().max(2 * ROM_N_SIZE as usize);
+        buffer.resize(size, 0xFF);
+        let banks = header.rom_banks()?.max(size / ROM_N_SIZE as usize);
+        self.rom = buffer.repeat(banks * ROM_N_SIZE as usize / size).into();
+        self.current_rom = 1;
+        Ok(())
     }
-}
\ No newline at end of file
+}
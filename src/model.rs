@@ -0,0 +1,86 @@
+/// Game Boy hardware revisions, which differ in the state their boot ROM leaves behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// The early DMG boot ROM, only in the first Japanese units.
+    Dmg0,
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Sgb,
+    Sgb2,
+    /// Game Boy Color, which runs cartridges flagged for it in colour.
+    Cgb,
+}
+
+impl Model {
+    /// PC, AF, BC, DE, HL and SP as the boot ROM hands over at 0x0100. The DMG and MGB boot ROMs
+    /// leave the half carry and carry flags set unless the header checksum is 0.
+    pub fn post_boot_registers(&self, header_checksum: u8) -> [u16; 6] {
+        let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        match self {
+            Model::Dmg0 => [0x0100, 0x0100, 0xFF13, 0x00C1, 0x8403, 0xFFFE],
+            Model::Dmg => [0x0100, 0x0100 | checksum_flags, 0x0013, 0x00D8, 0x014D, 0xFFFE],
+            Model::Mgb => [0x0100, 0xFF00 | checksum_flags, 0x0013, 0x00D8, 0x014D, 0xFFFE],
+            Model::Sgb => [0x0100, 0x0100, 0x0014, 0x0000, 0xC060, 0xFFFE],
+            Model::Sgb2 => [0x0100, 0xFF00, 0x0014, 0x0000, 0xC060, 0xFFFE],
+            Model::Cgb => [0x0100, 0x1180, 0x0000, 0xFF56, 0x000D, 0xFFFE],
+        }
+    }
+
+    /// The 16-bit divider DIV is the upper byte of. The SGB boot ROMs wait on the SNES and the
+    /// CGB one takes longer for some headers than others, so theirs vary and 0 is as good as any.
+    pub fn post_boot_divider(&self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 | Model::Cgb => 0x0000,
+        }
+    }
+
+    /// Video, timer, serial and interrupt registers after the boot ROM, the sound registers are
+    /// left to the APU. Both object palettes are never written and hold whatever they powered
+    /// up with.
+    pub fn post_boot_io(&self) -> Vec<(u16, u8)> {
+        let stat = if *self == Model::Dmg0 { 0x81 } else { 0x85 };
+        let mut registers = vec![
+            (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF02, 0x7E), (0xFF05, 0x00), (0xFF06, 0x00),
+            (0xFF07, 0xF8), (0xFF0F, 0xE1), (0xFF40, 0x91), (0xFF41, stat), (0xFF42, 0x00),
+            (0xFF43, 0x00), (0xFF44, 0x00), (0xFF45, 0x00), (0xFF46, 0xFF), (0xFF47, 0xFC),
+            (0xFF48, 0xFF), (0xFF49, 0xFF), (0xFF4A, 0x00), (0xFF4B, 0x00), (0xFFFF, 0x00),
+        ];
+        if *self == Model::Cgb {
+            // Normal speed, bank 0 of VRAM and 1 of WRAM, objects by OAM index and every background
+            // colour white
+            registers.extend([(0xFF4D, 0x7E), (0xFF4F, 0x00), (0xFF70, 0x01), (0xFF6C, 0x00), (0xFF68, 0x80)]);
+            registers.extend([(0xFF69, 0xFF); 64]);
+        }
+        registers
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::Cgb
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    /// Bytes in the boot ROM of this model.
+    pub fn boot_rom_size(&self) -> usize {
+        match self {
+            Model::Cgb => 0x0900,
+            _ => 0x0100,
+        }
+    }
+
+    /// The same registers at power on, where the boot ROM starts from.
+    pub fn power_on_io(&self) -> Vec<(u16, u8)> {
+        self.post_boot_io().into_iter().map(|(address, value)| match address {
+            0xFF00 => (address, 0xCF),
+            0xFF0F => (address, 0xE0),
+            0xFF41 => (address, 0x80),
+            0xFF46 | 0xFF48 | 0xFF49 => (address, value),
+            _ => (address, 0x00),
+        }).collect()
+    }
+}
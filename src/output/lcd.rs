@@ -12,18 +12,25 @@ use winit::event::Event;
 use winit::event::WindowEvent;
 use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
 use winit::platform::run_on_demand::EventLoopExtRunOnDemand;
+use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::Window;
-use crate::output::Output;
+use crate::input::Button;
+use crate::output::{Hotkey, Output};
 
 pub struct LCD {
     size: u32,
+    width: usize,
+    height: usize,
     pixels: Pixels<'static>,
     window: &'static Window,
     event_loop: EventLoop<()>,
+    shift: bool,
+    hotkeys: Vec<Hotkey>,
+    keys: Vec<(Button, bool)>,
 }
 impl Output for LCD {
     fn write_pixel(&mut self, x: u16, y: u16, color: u8, pallette: bool, _: u8) {
-        if x >= 160 || y >= 144 {
+        if x as usize >= self.width || y as usize >= self.height {
             return;
         }
         let colors = match pallette {
@@ -32,13 +39,37 @@ impl Output for LCD {
         };
         let c = colors[color as usize];
 
-        let x = x as usize;
-        let y = y as usize;
+        let offset = x as usize * 4 + y as usize * 4 * self.width;
         let frame: &mut [u8] = self.pixels.frame_mut();
-        frame[x * 4 + y * 4 * 160 + 0] = c;
-        frame[x * 4 + y * 4 * 160 + 1] = (c as f64 * 1.33) as u8;
-        frame[x * 4 + y * 4 * 160 + 2] = c;
-        frame[x * 4 + y * 4 * 160 + 3] = 255;
+        frame[offset] = c;
+        frame[offset + 1] = (c as f64 * 1.33) as u8;
+        frame[offset + 2] = c;
+        frame[offset + 3] = 255;
+    }
+
+    fn write_color(&mut self, x: u16, y: u16, color: u16) {
+        if x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+        let offset = x as usize * 4 + y as usize * 4 * self.width;
+        let frame: &mut [u8] = self.pixels.frame_mut();
+        for (i, shift) in [0, 5, 10].into_iter().enumerate() {
+            let channel = (color >> shift & 0x1F) as u8;
+            frame[offset + i] = channel << 3 | channel >> 2;
+        }
+        frame[offset + 3] = 255;
+    }
+
+    fn set_frame_size(&mut self, width: u16, height: u16) {
+        let (width, height) = (width as u32, height as u32);
+        if (width as usize, height as usize) == (self.width, self.height) {
+            return;
+        }
+        self.width = width as usize;
+        self.height = height as usize;
+        self.pixels.resize_buffer(width, height).unwrap();
+        self.pixels.resize_surface(width * self.size * 2, height * self.size * 2).unwrap();
+        let _ = self.window.request_inner_size(LogicalSize::new((width * self.size) as f64, (height * self.size) as f64));
     }
 
     fn refresh(&mut self) -> bool {
@@ -50,6 +81,50 @@ impl Output for LCD {
                     event: WindowEvent::CloseRequested,
                     window_id,
                 } if window_id == self.window.id() => elwt.exit(),
+                Event::WindowEvent {
+                    event: WindowEvent::ModifiersChanged(modifiers),
+                    ..
+                } => self.shift = modifiers.state().shift_key(),
+                Event::WindowEvent {
+                    event: WindowEvent::KeyboardInput { event: KeyEvent { physical_key: PhysicalKey::Code(KeyCode::Backspace), state, repeat: false, .. }, .. },
+                    ..
+                } => self.hotkeys.push(Hotkey::Rewind(state == ElementState::Pressed)),
+                Event::WindowEvent {
+                    event: WindowEvent::KeyboardInput { event: KeyEvent { physical_key: PhysicalKey::Code(key), state, repeat: false, .. }, .. },
+                    ..
+                } if LCD::button(key).is_some() => self.keys.push((LCD::button(key).unwrap(), state == ElementState::Pressed)),
+                Event::WindowEvent {
+                    event: WindowEvent::KeyboardInput { event: KeyEvent { physical_key: PhysicalKey::Code(key), state: ElementState::Pressed, repeat: false, .. }, .. },
+                    ..
+                } => {
+                    let speed = match key {
+                        KeyCode::Equal => Some(Hotkey::SpeedUp),
+                        KeyCode::Minus => Some(Hotkey::SlowDown),
+                        KeyCode::Tab => Some(Hotkey::Uncapped),
+                        _ => None,
+                    };
+                    if let Some(hotkey) = speed {
+                        self.hotkeys.push(hotkey);
+                        return;
+                    }
+                    // F1-F9 load the matching slot, with shift held they save it
+                    let slot = match key {
+                        KeyCode::F1 => 1,
+                        KeyCode::F2 => 2,
+                        KeyCode::F3 => 3,
+                        KeyCode::F4 => 4,
+                        KeyCode::F5 => 5,
+                        KeyCode::F6 => 6,
+                        KeyCode::F7 => 7,
+                        KeyCode::F8 => 8,
+                        KeyCode::F9 => 9,
+                        _ => return,
+                    };
+                    self.hotkeys.push(match self.shift {
+                        true => Hotkey::SaveState(slot),
+                        false => Hotkey::LoadState(slot),
+                    });
+                }
                 Event::AboutToWait => {
                     self.window.request_redraw();
                 }
@@ -62,9 +137,32 @@ impl Output for LCD {
         self.pixels.render().unwrap();
         true
     }
+
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    fn take_keys(&mut self) -> Vec<(Button, bool)> {
+        std::mem::take(&mut self.keys)
+    }
 }
 
 impl LCD {
+    /// Arrow keys for the d-pad, X and Z for A and B, Enter for Start and Space for Select.
+    fn button(key: KeyCode) -> Option<Button> {
+        Some(match key {
+            KeyCode::KeyX => Button::A,
+            KeyCode::KeyZ => Button::B,
+            KeyCode::Space => Button::Select,
+            KeyCode::Enter => Button::Start,
+            KeyCode::ArrowRight => Button::Right,
+            KeyCode::ArrowLeft => Button::Left,
+            KeyCode::ArrowUp => Button::Up,
+            KeyCode::ArrowDown => Button::Down,
+            _ => return None,
+        })
+    }
+
     pub fn new(scale: u32) -> Self {
         let event_loop = EventLoop::new().unwrap();
         let window = Box::leak(Box::new(WindowBuilder::new()
@@ -77,9 +175,14 @@ impl LCD {
         let pixels = Pixels::new(160 * 1, 144 * 1, surface_texture).unwrap();
         LCD {
             size: scale,
+            width: 160,
+            height: 144,
             pixels,
             window,
-            event_loop
+            event_loop,
+            shift: false,
+            hotkeys: vec![],
+            keys: vec![],
         }
     }
 }
@@ -9,7 +9,7 @@ pub struct LCDD {
 }
 impl Output for LCDD {
     fn write_pixel(&mut self, x: u16, y: u16, color: u8, pallette: bool, debug: u8) {
-        if x >= 200 {
+        if x >= 200 || y >= 200 {
             return;
         }
         let colors = match pallette {
@@ -8,12 +8,44 @@ use std::fmt::Debug;
 use std::io::{Write};
 use async_trait::async_trait;
 use colored::{Colorize};
+use crate::input::Button;
+
+/// Emulator actions bound to keys of a frontend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState(u8),
+    LoadState(u8),
+    /// Sent when the rewind key goes down and again when it comes up.
+    Rewind(bool),
+    /// Doubles or halves the speed within 0.25x-8x.
+    SpeedUp,
+    SlowDown,
+    /// Switches between running uncapped and at 1x.
+    Uncapped,
+}
 
 #[async_trait]
 pub trait Output {
     fn write_pixel(&mut self, _: u16, _: u16, _: u8, _: bool, _: u8) {}
+    /// A pixel in Game Boy Color mode as RGB555, red in the low bits. Frontends without colour
+    /// get a grey shade through `write_pixel`.
+    fn write_color(&mut self, x: u16, y: u16, color: u16) {
+        self.write_pixel(x, y, crate::palette::shade(color), false, 0);
+    }
+    /// Size of the frames to come, 160x144 or 256x224 for the border of a Super Game Boy.
+    fn set_frame_size(&mut self, _width: u16, _height: u16) {}
     fn refresh(&mut self) -> bool {
         true
     }
     fn set_diagnostics(&mut self, diagnostics: String) {}
+    /// Called whenever the cartridge turns its rumble motor on or off.
+    fn set_rumble(&mut self, _: bool) {}
+    /// Hotkeys pressed since the last call.
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        vec![]
+    }
+    /// Game Boy buttons that went down or up on the keyboard since the last call.
+    fn take_keys(&mut self) -> Vec<(Button, bool)> {
+        vec![]
+    }
 }
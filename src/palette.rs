@@ -0,0 +1,112 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Grey shade of an RGB555 colour, from 0 for black to 3 for white like `Ppu::framebuffer`.
+pub fn shade(color: u16) -> u8 {
+    let sum = (color & 0x1F) + (color >> 5 & 0x1F) + (color >> 10 & 0x1F);
+    ((sum * 3 + 46) / 93) as u8
+}
+
+/// Eight palettes of four RGB555 colours, the memory behind BCPS/BCPD or OCPS/OCPD.
+#[derive(Clone)]
+pub struct ColorPalettes {
+    data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        ColorPalettes::new()
+    }
+}
+
+impl ColorPalettes {
+    pub fn new() -> ColorPalettes {
+        ColorPalettes {
+            data: [0; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    /// The specification register, bit 6 always reads as set.
+    pub fn read_spec(&self) -> u8 {
+        (self.auto_increment as u8) << 7 | 0x40 | self.index
+    }
+
+    pub fn write_spec(&mut self, value: u8) {
+        self.auto_increment = value & 0x80 != 0;
+        self.index = value & 0x3F;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    /// Writes at the index, which moves on to the next byte with auto-increment. Reads never move it.
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// RGB555 colour with red in the low bits, of a palette from 0 to 7 and a colour from 0 to 3.
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = ((palette & 0x07) * 8 + (color & 0x03) * 2) as usize;
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) & 0x7FFF
+    }
+
+    /// Writes the four colours of a palette directly, without going through the index.
+    pub fn set_palette(&mut self, palette: u8, colors: [u16; 4]) {
+        for (color, value) in colors.into_iter().enumerate() {
+            let offset = (palette & 0x07) as usize * 8 + color * 2;
+            self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub fn data(&self) -> &[u8; 64] {
+        &self.data
+    }
+
+    pub fn set_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.read_spec());
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.data)?;
+        self.write_spec(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::palette::ColorPalettes;
+
+    #[test]
+    fn auto_increment() {
+        let mut palettes = ColorPalettes::new();
+        palettes.write_spec(0x80 | 0x3E);
+        assert_eq!(palettes.read_spec(), 0xFE);
+        for value in [0x1F, 0x00, 0xE0, 0x03] {
+            palettes.write_data(value);
+        }
+        assert_eq!(palettes.read_spec(), 0xC2);
+        assert_eq!(palettes.color(7, 3), 0x001F);
+        assert_eq!(palettes.color(0, 0), 0x03E0);
+
+        palettes.write_spec(0x02);
+        palettes.write_data(0xFF);
+        palettes.write_data(0xFF);
+        assert_eq!(palettes.read_data(), 0xFF);
+        assert_eq!(palettes.read_spec(), 0x42);
+        assert_eq!(palettes.color(0, 1), 0x00FF);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::bus::{Bus, OAM};
-use crate::fetcher::Fetcher;
+use crate::fetcher::{Fetcher, ATTR_PRIORITY};
 use crate::output::Output;
+use crate::palette;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::window_fetcher::WindowFetcher;
 use bitfield::Bit;
 use std::alloc::System;
@@ -10,6 +12,9 @@ use std::ops::Deref;
 use std::thread;
 
 const PPU_LINE_LENGTH: usize = 456;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+#[derive(Clone, Copy)]
 pub struct OAM {
     address: u16,
     y: u8,
@@ -19,6 +24,9 @@ pub struct OAM {
     flip_x: bool,
     flip_y: bool,
     priority: bool,
+    /// CGB palette and VRAM bank, 0 outside CGB mode.
+    cgb_palette: u8,
+    bank: u8,
     data0: u8,
     data1: u8,
 }
@@ -39,6 +47,40 @@ impl OAM {
         self.flip_x = tmp.bit(5);
         self.flip_y = tmp.bit(6);
         self.priority = tmp.bit(7);
+        if bus.cgb {
+            self.cgb_palette = tmp & 0x07;
+            self.bank = tmp.bit(3) as u8;
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.address);
+        state.u8(self.y);
+        state.u8(self.x);
+        state.u8(self.tile_index);
+        state.bool(self.palette);
+        state.bool(self.flip_x);
+        state.bool(self.flip_y);
+        state.bool(self.priority);
+        state.u8(self.cgb_palette);
+        state.u8(self.bank);
+        state.u8(self.data0);
+        state.u8(self.data1);
+    }
+    fn load_state(state: &mut StateReader) -> Result<OAM, StateError> {
+        Ok(OAM {
+            address: state.u16()?,
+            y: state.u8()?,
+            x: state.u8()?,
+            tile_index: state.u8()?,
+            palette: state.bool()?,
+            flip_x: state.bool()?,
+            flip_y: state.bool()?,
+            priority: state.bool()?,
+            cgb_palette: state.u8()?,
+            bank: state.u8()?,
+            data0: state.u8()?,
+            data1: state.u8()?,
+        })
     }
     pub fn empty() -> OAM {
         OAM {
@@ -50,31 +92,14 @@ impl OAM {
             flip_x: true,
             flip_y: true,
             priority: true,
+            cgb_palette: 0,
+            bank: 0,
             data0: 0,
             data1: 0,
         }
     }
 }
 
-impl Clone for OAM {
-    fn clone(&self) -> Self {
-        OAM {
-            address: 0,
-            y: 0,
-            x: 0,
-            tile_index: 0,
-            palette: false,
-            flip_x: false,
-            flip_y: false,
-            priority: false,
-            data0: 0,
-            data1: 0,
-        }
-    }
-}
-
-impl Copy for OAM {}
-
 #[derive(Clone)]
 pub enum PpuState {
     HBlank = 0,
@@ -83,6 +108,19 @@ pub enum PpuState {
     PixelTransfer = 3,
 }
 
+impl PpuState {
+    pub(crate) fn from_mode(mode: u8) -> Result<PpuState, StateError> {
+        match mode {
+            0 => Ok(PpuState::HBlank),
+            1 => Ok(PpuState::VBlank),
+            2 => Ok(PpuState::OAMFetch),
+            3 => Ok(PpuState::PixelTransfer),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Ppu {
     pub ticks: usize,
     pub state: PpuState,
@@ -95,7 +133,13 @@ pub struct Ppu {
     fetcher: Fetcher,
     window_fetcher: WindowFetcher,
     target_ticks: usize,
-    cgb_mode: bool,
+    pub(crate) cgb_mode: bool,
+    /// A DMG cartridge on a CGB, where BGP and OBP pick from the first colour palettes.
+    pub(crate) compat_mode: bool,
+    pub framebuffer: Vec<u8>,
+    /// RGB555 colours of the last frame in CGB and compatibility mode.
+    pub color_framebuffer: Vec<u16>,
+    pub frame_complete: bool,
 }
 
 impl Ppu {
@@ -113,6 +157,142 @@ impl Ppu {
             fetcher: Fetcher::new(),
             window_fetcher: WindowFetcher::new(),
             cgb_mode: false,
+            compat_mode: false,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_complete: false,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.usize(self.ticks);
+        state.u8(self.state.clone() as u8);
+        state.usize(self.oambuffer.len());
+        for oam in self.oambuffer.iter() {
+            oam.save_state(state);
+        }
+        state.i16(self.x);
+        state.i16(self.x_shift);
+        state.i16(self.y);
+        state.i16(self.y_shift);
+        state.bool(self.window_y_hit);
+        self.fetcher.save_state(state);
+        self.window_fetcher.save_state(state);
+        state.usize(self.target_ticks);
+        state.bool(self.cgb_mode);
+        state.bool(self.compat_mode);
+        state.bytes(&self.framebuffer);
+        for color in self.color_framebuffer.iter() {
+            state.u16(*color);
+        }
+        state.bool(self.frame_complete);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ticks = state.usize()?;
+        self.state = PpuState::from_mode(state.u8()?)?;
+        self.oambuffer.clear();
+        let sprites = state.usize()?;
+        if sprites > 10 {
+            return Err(StateError::Corrupt);
+        }
+        for _ in 0..sprites {
+            self.oambuffer.push(OAM::load_state(state)?);
+        }
+        self.x = state.i16()?;
+        self.x_shift = state.i16()?;
+        self.y = state.i16()?;
+        self.y_shift = state.i16()?;
+        self.window_y_hit = state.bool()?;
+        self.fetcher.load_state(state)?;
+        self.window_fetcher.load_state(state)?;
+        self.target_ticks = state.usize()?;
+        self.cgb_mode = state.bool()?;
+        self.compat_mode = state.bool()?;
+        state.bytes_into(&mut self.framebuffer)?;
+        for color in self.color_framebuffer.iter_mut() {
+            *color = state.u16()?;
+        }
+        self.frame_complete = state.bool()?;
+        Ok(())
+    }
+
+    /// Drops the progress within the current line and starts it over, for states that only
+    /// know LY and not where the pixel pipeline was.
+    pub fn restart_line(&mut self, bus: &mut Bus) {
+        let framebuffer = std::mem::take(&mut self.framebuffer);
+        let color_framebuffer = std::mem::take(&mut self.color_framebuffer);
+        let (cgb_mode, compat_mode) = (self.cgb_mode, self.compat_mode);
+        *self = Ppu::new();
+        self.framebuffer = framebuffer;
+        self.color_framebuffer = color_framebuffer;
+        self.cgb_mode = cgb_mode;
+        self.compat_mode = compat_mode;
+        let state = match bus.get_ly() {
+            ..144 => PpuState::OAMFetch,
+            _ => PpuState::VBlank,
+        };
+        self.set_ppu_state(bus, state);
+    }
+
+    /// Stores the shade of a pixel in the framebuffer, 0 being the darkest and 3 the lightest,
+    /// and forwards it to the output.
+    fn write_pixel(framebuffer: &mut [u8], output: &mut Box<dyn Output>, x: i16, y: u8, color: u8, palette: bool, debug: u8) {
+        if x >= 0 && (x as usize) < SCREEN_WIDTH && (y as usize) < SCREEN_HEIGHT {
+            framebuffer[y as usize * SCREEN_WIDTH + x as usize] = match palette {
+                false => color,
+                true => 3 - color,
+            };
+        }
+        output.write_pixel(x as u16, y as u16, color, palette, debug);
+    }
+
+    /// Stores an RGB555 colour in CGB mode, with a grey shade for `framebuffer`.
+    fn write_color(framebuffer: &mut [u8], color_framebuffer: &mut [u16], output: &mut Box<dyn Output>, x: i16, y: u8, color: u16) {
+        if x >= 0 && (x as usize) < SCREEN_WIDTH && (y as usize) < SCREEN_HEIGHT {
+            framebuffer[y as usize * SCREEN_WIDTH + x as usize] = palette::shade(color);
+            color_framebuffer[y as usize * SCREEN_WIDTH + x as usize] = color;
+        }
+        output.write_color(x as u16, y as u16, color);
+    }
+
+    /// Stores the shade BGP or OBP give a pixel under the Super Game Boy, which colours and shows
+    /// the whole frame once it is complete.
+    fn write_shade(framebuffer: &mut [u8], x: i16, y: u8, shade: u8) {
+        if x >= 0 && (x as usize) < SCREEN_WIDTH && (y as usize) < SCREEN_HEIGHT {
+            framebuffer[y as usize * SCREEN_WIDTH + x as usize] = 3 - shade;
+        }
+    }
+
+    /// Shows a background or window pixel, or the sprite over it.
+    fn draw(&mut self, bus: &Bus, output: &mut Box<dyn Output>, bg: u8, debug: u8) {
+        let y = bus.get_ly();
+        match (self.sprite_pixel(bus, bg), self.cgb_mode) {
+            (Some((color, oam)), true) => {
+                let color = bus.obj_palettes.color(oam.cgb_palette, color);
+                Ppu::write_color(&mut self.framebuffer, &mut self.color_framebuffer, output, self.x, y, color);
+            }
+            (Some((color, oam)), false) if self.compat_mode => {
+                let obp = bus.get(if oam.palette { 0xFF49 } else { 0xFF48 });
+                let color = bus.obj_palettes.color(oam.palette as u8, obp >> (color * 2) & 0x03);
+                Ppu::write_color(&mut self.framebuffer, &mut self.color_framebuffer, output, self.x, y, color);
+            }
+            (Some((color, oam)), false) if bus.sgb.is_some() => {
+                let obp = bus.get(if oam.palette { 0xFF49 } else { 0xFF48 });
+                Ppu::write_shade(&mut self.framebuffer, self.x, y, obp >> (color * 2) & 0x03);
+            }
+            (Some((color, oam)), false) => Ppu::write_pixel(&mut self.framebuffer, output, self.x, y, color, oam.palette, 2),
+            (None, true) => {
+                let color = bus.bg_palettes.color(bg >> 2, bg & 0x03);
+                Ppu::write_color(&mut self.framebuffer, &mut self.color_framebuffer, output, self.x, y, color);
+            }
+            (None, false) if self.compat_mode => {
+                let color = bus.bg_palettes.color(0, bus.get(0xFF47) >> (bg * 2) & 0x03);
+                Ppu::write_color(&mut self.framebuffer, &mut self.color_framebuffer, output, self.x, y, color);
+            }
+            (None, false) if bus.sgb.is_some() => {
+                Ppu::write_shade(&mut self.framebuffer, self.x, y, bus.get(0xFF47) >> (bg * 2) & 0x03);
+            }
+            (None, false) => Ppu::write_pixel(&mut self.framebuffer, output, self.x, y, bg, false, debug),
         }
     }
     fn set_ppu_state(&mut self, bus: &mut Bus, state: PpuState) {
@@ -120,6 +300,9 @@ impl Ppu {
         let val = (bus.registers.lcds & 0b11111100) | state.clone() as u8;
         self.state = state.clone();
         bus.set(0xFF41, val);
+        if matches!(state, PpuState::HBlank) {
+            bus.hblank_dma();
+        }
         self.target_ticks = match state {
             PpuState::OAMFetch => PPU_LINE_LENGTH - 80,
             PpuState::PixelTransfer => self.target_ticks - 172,
@@ -231,8 +414,8 @@ impl Ppu {
                     addr += ((bus.get_ly() + 16 - oam.y) * 2) as u16;
                 }
 
-                oam.data0 = bus._get(addr);
-                oam.data1 = bus._get(addr + 1);
+                oam.data0 = bus.vram(oam.bank, addr);
+                oam.data1 = bus.vram(oam.bank, addr + 1);
 
                 self.oambuffer.push(oam);
 
@@ -249,7 +432,8 @@ impl Ppu {
             }
         }
 
-        if self.cgb_mode == false {
+        // The sort is stable, so sprites at the same X keep their OAM order
+        if bus.obj_priority_by_x() {
             self.oambuffer.sort_by_key(|oam| oam.x);
         }
 
@@ -257,11 +441,12 @@ impl Ppu {
         i
     }
 
-    fn oam_tranfer(&mut self, bus: &mut Bus, transparent_bg: bool, mut output: &mut Box<dyn Output>) -> bool {
+    /// The first opaque sprite pixel at the current X, unless the background covers it. A sprite
+    /// behind the background still hides the sprites after it.
+    fn sprite_pixel(&self, bus: &Bus, bg: u8) -> Option<(u8, OAM)> {
         if !bus.get_ldlc_obj_enable() {
-            return false;
+            return None;
         }
-        let mut final_pixel = (0, false);
         for oam in self.oambuffer.iter() {
             if self.x + 8 - (oam.x as i16) < 8 && self.x + 8 - (oam.x as i16) >= 0 {
                 let mut bit_shift = 7 - self.x.saturating_sub_unsigned(oam.x as u16);
@@ -271,24 +456,16 @@ impl Ppu {
 
                 let mut sprite_pixel = oam.data0.overflowing_shr(bit_shift as u32).0 & 0x1;
                 sprite_pixel |= (oam.data1.overflowing_shr(bit_shift as u32).0 & 0x1) << 1;
-                if !oam.priority || transparent_bg {
-                    if sprite_pixel != 0 {
-                        final_pixel.0 = sprite_pixel;
-                        final_pixel.1 = oam.palette;
-                        output.write_pixel(
-                            self.x as u16,
-                            bus.get_ly() as u16,
-                            final_pixel.0,
-                            final_pixel.1,
-                            2,
-                        );
-                        return true
-                    }
+                if sprite_pixel == 0 {
+                    continue;
                 }
+                // In CGB mode, clearing LCDC bit 0 puts every sprite in front
+                let bg_priority = !self.cgb_mode || bus.get_ldlc_bd_window_enable();
+                let behind = bg & 0x03 != 0 && bg_priority && (oam.priority || bg & ATTR_PRIORITY != 0);
+                return (!behind).then_some((sprite_pixel, *oam));
             }
-
         }
-        false
+        None
     }
 
     fn pixel_tranfer(&mut self, bus: &mut Bus, mut output: &mut Box<dyn Output>, ticks: usize) -> usize {
@@ -307,20 +484,14 @@ impl Ppu {
 
                 while !self.window_fetcher.fifo_bg.is_empty() {
                     pixel = self.window_fetcher.fifo_bg.pop().unwrap().to_owned();
-                    let transparent_bg = pixel == 0;
-                    if !self.oam_tranfer(bus, transparent_bg, output) {
-                        output.write_pixel(self.x as u16, bus.get_ly() as u16, pixel, false, debug);
-                    }
+                    self.draw(bus, output, pixel, debug);
                     self.x += 1;
                 }
             } else {
                 self.fetcher.tick(bus);
                 while !self.fetcher.fifo_bg.is_empty() {
                     pixel = self.fetcher.fifo_bg.pop().unwrap().to_owned();
-                    let transparent_bg = pixel == 0;
-                    if !self.oam_tranfer(bus, transparent_bg, output){
-                        output.write_pixel(self.x as u16, bus.get_ly() as u16, pixel, false, debug);
-                    }
+                    self.draw(bus, output, pixel, debug);
                     self.x += 1;
                 }
             }
@@ -344,7 +515,8 @@ impl Ppu {
 
     fn hblank(&mut self, bus: &mut Bus, ticks: usize) ->usize{
         let mut i = 0;
-        while i < ticks {
+        // Pixel transfer can run up to the end of the line, in which case no time is left for HBlank
+        while i < ticks && self.ticks > self.target_ticks {
             self.ticks = self.ticks.saturating_sub(4);
             i += 1;
             if self.ticks <= self.target_ticks {
@@ -370,6 +542,7 @@ impl Ppu {
             if bus.get_ldlc_stat_vblank_stat_int() {
                 bus.set_int_request_lcd(true);
             }
+            self.frame_complete = true;
             self.set_ppu_state(bus, PpuState::VBlank);
         } else {
             self.set_ppu_state(bus, PpuState::OAMFetch);
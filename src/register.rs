@@ -1,5 +1,6 @@
 use bitfield::{Bit, BitMut};
 
+#[derive(Clone)]
 pub struct Register {
     pub(crate) value: u8
 }
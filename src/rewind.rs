@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+
+/// Ring of machine snapshots for stepping back in time. Only the newest snapshot is kept whole,
+/// each older one is the compressed difference to the snapshot after it, so the oldest can be
+/// dropped without touching the rest.
+pub struct RewindBuffer {
+    interval: usize,
+    budget: usize,
+    frames: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl RewindBuffer {
+    /// Snapshots every `interval` frames, keeping at most about `budget` bytes of them.
+    pub fn new(interval: usize, budget: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Counts a frame, true when it is time for a new snapshot.
+    pub fn frame(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            return true;
+        }
+        false
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            self.used -= newest.len();
+            let delta = encode(&state, &newest);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.used += state.len();
+        self.newest = Some(state);
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Takes the newest snapshot, the one before it takes its place.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.used -= newest.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            self.used -= delta.len();
+            let previous = decode(&newest, &delta);
+            self.used += previous.len();
+            self.newest = Some(previous);
+        }
+        self.frames = 0;
+        Some(newest)
+    }
+
+    /// Number of snapshots that can still be rewound to.
+    pub fn len(&self) -> usize {
+        self.newest.iter().count() + self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes taken by the snapshots.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.frames = 0;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// XOR of `to` against `from` as runs of unchanged bytes and literal changes. Most of memory is
+/// the same from one snapshot to the next, so WRAM and VRAM come down to a few runs.
+fn encode(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    write_varint(&mut delta, to.len());
+    let byte = |i: usize| to[i] ^ from.get(i).copied().unwrap_or(0);
+    let mut i = 0;
+    while i < to.len() {
+        let start = i;
+        while i < to.len() && byte(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+        let start = i;
+        // A lone unchanged byte costs less as part of the literal than as a run of its own
+        while i < to.len() && (byte(i) != 0 || (i + 1 < to.len() && byte(i + 1) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+        delta.extend((start..i).map(byte));
+    }
+    delta
+}
+
+fn decode(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let len = read_varint(delta, &mut position);
+    let mut to: Vec<u8> = (0..len).map(|i| from.get(i).copied().unwrap_or(0)).collect();
+    let mut i = 0;
+    while i < len {
+        i += read_varint(delta, &mut position);
+        let literal = read_varint(delta, &mut position);
+        for byte in &mut to[i..i + literal] {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        i += literal;
+    }
+    to
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewind::{decode, encode, RewindBuffer};
+
+    #[test]
+    fn delta() {
+        let from: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut to = from.clone();
+        to[10] = 0xFF;
+        to[500..510].fill(0);
+        to.extend_from_slice(&[1, 2, 3]);
+        let delta = encode(&from, &to);
+        assert!(delta.len() < 32);
+        assert_eq!(decode(&from, &delta), to);
+        assert_eq!(decode(&to, &encode(&to, &from)), from);
+    }
+
+    #[test]
+    fn budget() {
+        let mut rewind = RewindBuffer::new(2, 4000);
+        let states: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 1000]).collect();
+        for state in states.iter() {
+            assert!(!rewind.frame());
+            assert!(rewind.frame());
+            rewind.push(state.clone());
+        }
+        assert!(rewind.used() <= 4000);
+        let len = rewind.len();
+        assert_eq!(len, 3);
+        for state in states.iter().rev().take(len) {
+            assert_eq!(rewind.pop().as_ref(), Some(state));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.used(), 0);
+    }
+}
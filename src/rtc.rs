@@ -0,0 +1,372 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitfield::{Bit, BitMut};
+use crate::emulator::CLOCK_SPEED;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0A;
+pub const RTC_DAYS_LOW: u8 = 0x0B;
+pub const RTC_DAYS_HIGH: u8 = 0x0C;
+/// Size of the clock state other emulators append to the RAM in MBC3 save files.
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+/// What drives the cartridge clock forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// Follows the host clock, also while the emulator is not running.
+    WallClock,
+    /// Follows emulated cycles, so runs are reproducible.
+    Emulated,
+}
+
+/// Real-time clock of MBC3 cartridges.
+#[derive(Clone)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halt: bool,
+    pub carry: bool,
+    pub latched: [u8; 5],
+    latch_armed: bool,
+    clock: RtcClock,
+    cycles: usize,
+    last_update: SystemTime,
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            clock,
+            cycles: 0,
+            last_update: SystemTime::now(),
+        }
+    }
+
+    pub fn clock(&self) -> RtcClock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.update();
+        self.clock = clock;
+        self.cycles = 0;
+        self.last_update = SystemTime::now();
+    }
+
+    /// Advances the emulated clock by a number of T-cycles, the wall clock ignores this.
+    pub fn tick(&mut self, cycles: usize) {
+        if self.clock != RtcClock::Emulated {
+            return;
+        }
+        self.cycles += cycles;
+        if self.cycles >= CLOCK_SPEED {
+            self.advance((self.cycles / CLOCK_SPEED) as u64);
+            self.cycles %= CLOCK_SPEED;
+        }
+    }
+
+    /// Catches the wall clock up with the host time.
+    fn update(&mut self) {
+        if self.clock != RtcClock::WallClock {
+            return;
+        }
+        let elapsed = SystemTime::now().duration_since(self.last_update).unwrap_or_default().as_secs();
+        self.last_update += Duration::from_secs(elapsed);
+        self.advance(elapsed);
+    }
+
+    pub fn advance(&mut self, mut seconds: u64) {
+        if self.halt {
+            return;
+        }
+        // A game can write values past 59 or 23, which only leave the range by wrapping at the
+        // limit of their bits, so those are counted out second by second
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.advance_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let seconds = self.seconds as u64 + seconds;
+        self.seconds = (seconds % 60) as u8;
+        let minutes = self.minutes as u64 + seconds / 60;
+        self.minutes = (minutes % 60) as u8;
+        let hours = self.hours as u64 + minutes / 60;
+        self.hours = (hours % 24) as u8;
+        let days = self.days as u64 + hours / 24;
+        if days >= 512 {
+            self.carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    /// Counts one second the way the counters do, each only carries into the next going from its
+    /// last valid value to 0.
+    fn advance_second(&mut self) {
+        if self.seconds != 59 {
+            self.seconds = (self.seconds + 1) & 0x3F;
+            return;
+        }
+        self.seconds = 0;
+        if self.minutes != 59 {
+            self.minutes = (self.minutes + 1) & 0x3F;
+            return;
+        }
+        self.minutes = 0;
+        if self.hours != 23 {
+            self.hours = (self.hours + 1) & 0x1F;
+            return;
+        }
+        self.hours = 0;
+        self.days = (self.days + 1) & 0x1FF;
+        if self.days == 0 {
+            self.carry = true;
+        }
+    }
+
+    /// Writing 0x00 and then 0x01 copies the running clock into the registers the game reads.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            for register in RTC_SECONDS..=RTC_DAYS_HIGH {
+                self.latched[(register - RTC_SECONDS) as usize] = self.get(register);
+            }
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    fn get(&self, register: u8) -> u8 {
+        match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAYS_LOW => self.days as u8,
+            RTC_DAYS_HIGH => {
+                let mut value = (self.days >> 8) as u8 & 0x01;
+                value.set_bit(6, self.halt);
+                value.set_bit(7, self.carry);
+                value
+            }
+            _ => panic!("No RTC register {:#02x}", register)
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        match register {
+            RTC_SECONDS => {
+                self.seconds = value & 0x3F;
+                self.cycles = 0;
+            }
+            RTC_MINUTES => self.minutes = value & 0x3F,
+            RTC_HOURS => self.hours = value & 0x1F,
+            RTC_DAYS_LOW => self.days = (self.days & 0x100) | value as u16,
+            RTC_DAYS_HIGH => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halt = value.bit(6);
+                self.carry = value.bit(7);
+            }
+            _ => panic!("No RTC register {:#02x}", register)
+        }
+        // Games check their writes by reading back, which only works if the latch follows
+        self.latched[(register - RTC_SECONDS) as usize] = self.get(register);
+    }
+
+    /// Clock and latched registers as 32-bit little endian words, followed by a 64-bit timestamp.
+    pub fn to_footer(&mut self) -> [u8; RTC_FOOTER_SIZE] {
+        self.update();
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        for register in RTC_SECONDS..=RTC_DAYS_HIGH {
+            let i = (register - RTC_SECONDS) as usize;
+            footer[i * 4] = self.get(register);
+            footer[20 + i * 4] = self.latched[i];
+        }
+        let timestamp = self.last_update.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        footer[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.seconds);
+        state.u8(self.minutes);
+        state.u8(self.hours);
+        state.u16(self.days);
+        state.bool(self.halt);
+        state.bool(self.carry);
+        state.bytes(&self.latched);
+        state.bool(self.latch_armed);
+        state.usize(self.cycles);
+        state.u64(self.last_update.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    }
+
+    /// Keeps the clock source of this session, a wall clock catches up with the time since the save.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = state.u8()?;
+        self.minutes = state.u8()?;
+        self.hours = state.u8()?;
+        self.days = state.u16()?;
+        self.halt = state.bool()?;
+        self.carry = state.bool()?;
+        state.bytes_into(&mut self.latched)?;
+        self.latch_armed = state.bool()?;
+        self.cycles = state.usize()?;
+        let timestamp = state.u64()?;
+        if self.clock == RtcClock::WallClock {
+            self.last_update = UNIX_EPOCH + Duration::from_secs(timestamp);
+            self.update();
+        }
+        Ok(())
+    }
+
+    /// Restores a footer, also the older 44 byte variant with a 32-bit timestamp.
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() < 44 {
+            return;
+        }
+        for register in RTC_SECONDS..=RTC_DAYS_HIGH {
+            self.write(register, footer[(register - RTC_SECONDS) as usize * 4]);
+        }
+        for i in 0..5 {
+            self.latched[i] = footer[20 + i * 4];
+        }
+        let mut timestamp = [0; 8];
+        let len = (footer.len() - 40).min(8);
+        timestamp[..len].copy_from_slice(&footer[40..40 + len]);
+        if self.clock == RtcClock::WallClock {
+            self.last_update = UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(timestamp));
+            self.update();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::CLOCK_SPEED;
+    use crate::rtc::{Rtc, RtcClock, RTC_DAYS_HIGH, RTC_DAYS_LOW, RTC_HOURS, RTC_MINUTES, RTC_SECONDS};
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn latching() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.tick(3 * CLOCK_SPEED);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 3);
+
+        rtc.tick(CLOCK_SPEED);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(RTC_SECONDS), 3);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 4);
+    }
+
+    #[test]
+    fn rollover() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(RTC_SECONDS, 59);
+        rtc.write(RTC_MINUTES, 59);
+        rtc.write(RTC_HOURS, 23);
+        rtc.write(RTC_DAYS_LOW, 0xFF);
+        rtc.write(RTC_DAYS_HIGH, 0x01);
+        rtc.tick(CLOCK_SPEED);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_MINUTES), 0);
+        assert_eq!(rtc.read(RTC_HOURS), 0);
+        assert_eq!(rtc.read(RTC_DAYS_LOW), 0);
+        assert_eq!(rtc.read(RTC_DAYS_HIGH), 0x80);
+
+        rtc.write(RTC_DAYS_HIGH, 0x00);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_DAYS_HIGH), 0x00);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(RTC_SECONDS, 0x3F);
+        rtc.write(RTC_MINUTES, 5);
+        rtc.tick(CLOCK_SPEED);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_MINUTES), 5);
+        rtc.tick(60 * CLOCK_SPEED);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_MINUTES), 6);
+
+        // Hours of 31 wrap to 0 an hour later and leave the days alone
+        rtc.write(RTC_HOURS, 31);
+        rtc.advance(3600 + 61);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_HOURS), 0);
+        assert_eq!(rtc.read(RTC_MINUTES), 7);
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+        assert_eq!(rtc.read(RTC_DAYS_LOW), 0);
+    }
+
+    #[test]
+    fn halt() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(RTC_DAYS_HIGH, 0x40);
+        rtc.tick(10 * CLOCK_SPEED);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_DAYS_HIGH), 0x40);
+
+        rtc.write(RTC_DAYS_HIGH, 0x00);
+        rtc.tick(CLOCK_SPEED / 2);
+        rtc.tick(CLOCK_SPEED / 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+    }
+
+    #[test]
+    fn footer() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(RTC_MINUTES, 12);
+        rtc.write(RTC_DAYS_HIGH, 0xC1);
+        latch(&mut rtc);
+        rtc.write(RTC_HOURS, 7);
+        let footer = rtc.to_footer();
+        assert_eq!(footer.len(), 48);
+
+        let mut loaded = Rtc::new(RtcClock::Emulated);
+        loaded.load_footer(&footer);
+        assert_eq!(loaded.minutes, 12);
+        assert_eq!(loaded.hours, 7);
+        assert_eq!(loaded.days, 0x100);
+        assert!(loaded.halt);
+        assert!(loaded.carry);
+        assert_eq!(loaded.latched, rtc.latched);
+        assert_eq!(loaded.read(RTC_HOURS), 7);
+    }
+
+    #[test]
+    fn wall_clock_ignores_cycles() {
+        let mut rtc = Rtc::new(RtcClock::WallClock);
+        rtc.tick(10 * CLOCK_SPEED);
+        latch(&mut rtc);
+        assert!(rtc.read(RTC_SECONDS) < 10);
+    }
+}
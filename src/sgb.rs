@@ -0,0 +1,528 @@
+use crate::output::Output;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Size of the picture the SNES shows, with the game screen in the middle of the border.
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+/// The game screen in cells of 8x8 pixels, which each get one of the four palettes.
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+const PACKET_SIZE: usize = 16;
+/// Bytes a *_TRN command reads from the screen, 256 tiles in the order they are shown.
+const TRANSFER_SIZE: usize = 0x1000;
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+/// A palette number for every cell, four to a byte.
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+/// Shown until a game sets its own palettes.
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+/// What the SNES copies from the next frame of the game screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    /// Half of the 256 border tiles, the upper one when set.
+    BorderTiles(bool),
+    BorderMap,
+}
+
+impl Transfer {
+    fn from_u8(value: u8) -> Result<Option<Transfer>, StateError> {
+        Ok(match value {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Attributes),
+            3 => Some(Transfer::BorderTiles(false)),
+            4 => Some(Transfer::BorderTiles(true)),
+            5 => Some(Transfer::BorderMap),
+            _ => return Err(StateError::Corrupt),
+        })
+    }
+
+    fn to_u8(transfer: Option<Transfer>) -> u8 {
+        match transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::Attributes) => 2,
+            Some(Transfer::BorderTiles(false)) => 3,
+            Some(Transfer::BorderTiles(true)) => 4,
+            Some(Transfer::BorderMap) => 5,
+        }
+    }
+}
+
+fn color_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) & 0x7FFF
+}
+
+/// The Super Game Boy side of the machine, which takes command packets through P1 and colours
+/// the game screen inside a border.
+#[derive(Clone)]
+pub struct Sgb {
+    packet: [u8; PACKET_SIZE],
+    /// Bits of the packet received so far, `None` until a reset pulse starts one.
+    bits: Option<usize>,
+    /// Packets so far of a command that spans several.
+    command: Vec<u8>,
+    /// P14 and P15 as last written.
+    lines: u8,
+    players: u8,
+    player: u8,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    /// Palette number of every cell.
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    /// MASK_EN, 1 freezes the screen, 2 blanks it to black and 3 to colour 0.
+    mask: u8,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    /// Border palettes 4-7, colour 0 shows the backdrop instead.
+    border_palettes: [[u16; 16]; 4],
+    /// Colours of the game screen as last shown, kept while it is frozen.
+    screen: Vec<u16>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            packet: [0; PACKET_SIZE],
+            bits: None,
+            command: vec![],
+            lines: 0x30,
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: 0,
+            transfer: None,
+            border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: [[0; 16]; 4],
+            screen: vec![DEFAULT_PALETTE[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    /// Follows writes to P1, which send packets a bit at a time. A reset pulse with P14 and P15
+    /// low starts one, then each of the 128 bits is P14 low for 0 or P15 low for 1, taken when
+    /// both go high again. Outside a packet, P15 going high moves on to the next player.
+    pub fn write_p1(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.lines, lines);
+        match (self.bits, previous, lines) {
+            (_, _, 0x00) => {
+                self.bits = Some(0);
+                self.packet = [0; PACKET_SIZE];
+            }
+            (Some(bits), 0x10 | 0x20, 0x30) => {
+                if previous == 0x10 {
+                    self.packet[bits / 8] |= 1 << (bits % 8);
+                }
+                if bits + 1 == PACKET_SIZE * 8 {
+                    self.bits = None;
+                    self.receive_packet();
+                } else {
+                    self.bits = Some(bits + 1);
+                }
+            }
+            (None, _, 0x30) if previous & 0x20 == 0 => self.player = (self.player + 1) % self.players,
+            _ => {}
+        }
+    }
+
+    /// Player whose buttons P1 reads.
+    pub fn player(&self) -> usize {
+        self.player as usize
+    }
+
+    /// What P1 reads with neither P14 nor P15 low, 0x0F for the first player down to 0x0C.
+    pub fn joypad_id(&self) -> u8 {
+        0x0F - self.player
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run(&command);
+        }
+    }
+
+    fn run(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attribute_blocks(data),
+            0x05 => self.attribute_lines(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            0x0A => self.palette_set(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::BorderTiles(data[1] & 0x01 != 0)),
+            0x14 => self.transfer = Some(Transfer::BorderMap),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.attribute_set(data[1]),
+            0x17 => self.mask = data[1] & 0x03,
+            // Sound, SNES code and the rest change nothing on screen
+            _ => {}
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12, colour 0 is shared by all four palettes.
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let shared = color_at(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        for color in 1..4 {
+            self.palettes[first][color] = color_at(data, 1 + color * 2);
+            self.palettes[second][color] = color_at(data, 7 + color * 2);
+        }
+    }
+
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        for block in data[2..].chunks_exact(6).take((data[1] & 0x1F) as usize) {
+            let (inside, border, outside) = (block[0] & 0x01 != 0, block[0] & 0x02 != 0, block[0] & 0x04 != 0);
+            let (inside_palette, border_palette, outside_palette) = (block[1] & 0x03, block[1] >> 2 & 0x03, block[1] >> 4 & 0x03);
+            // With only one side changed, the surrounding line goes along with it
+            let border_palette = match (inside, border, outside) {
+                (_, true, _) => Some(border_palette),
+                (true, false, false) => Some(inside_palette),
+                (false, false, true) => Some(outside_palette),
+                _ => None,
+            };
+            let [x1, y1, x2, y2] = [block[2], block[3], block[4], block[5]].map(|value| (value & 0x1F) as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                        inside.then_some(inside_palette)
+                    } else if (x1..=x2).contains(&x) && (y1..=y2).contains(&y) {
+                        border_palette
+                    } else {
+                        outside.then_some(outside_palette)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        for line in data[2..].iter().take(data[1] as usize) {
+            let (index, palette) = ((line & 0x1F) as usize, line >> 5 & 0x03);
+            if line & 0x80 != 0 && index < CELLS_Y {
+                self.attributes[index * CELLS_X..(index + 1) * CELLS_X].fill(palette);
+            } else if line & 0x80 == 0 && index < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let (after, before, on) = (data[1] & 0x03, data[1] >> 2 & 0x03, data[1] >> 4 & 0x03);
+        let at = (data[2] & 0x1F) as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if data[1] & 0x40 != 0 { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&at) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            if x < CELLS_X && y < CELLS_Y {
+                self.attributes[y * CELLS_X + x] = byte >> (6 - i % 4 * 2) & 0x03;
+            }
+            match vertical {
+                true if y + 1 >= CELLS_Y => (x, y) = (x + 1, 0),
+                true => y += 1,
+                false if x + 1 >= CELLS_X => (x, y) = (0, y + 1),
+                false => x += 1,
+            }
+        }
+    }
+
+    /// PAL_SET, which picks the four palettes from the ones PAL_TRN sent.
+    fn palette_set(&mut self, data: &[u8]) {
+        for (palette, number) in data[1..9].chunks_exact(2).enumerate() {
+            let number = (u16::from_le_bytes([number[0], number[1]]) & 0x01FF) as usize;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+        }
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    fn attribute_set(&mut self, value: u8) {
+        self.apply_attribute_file(value & 0x3F);
+        if value & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+        let data = &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..(file + 1) * ATTRIBUTE_FILE_SIZE];
+        for (i, palette) in self.attributes.iter_mut().enumerate() {
+            *palette = data[i / 4] >> (6 - i % 4 * 2) & 0x03;
+        }
+    }
+
+    /// The first 256 tiles on screen read back as 2bpp tile data, which is how the game sends
+    /// the SNES more than a packet holds.
+    fn transfer_data(framebuffer: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; TRANSFER_SIZE];
+        for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+            let (column, row) = (tile % CELLS_X, tile / CELLS_X);
+            for line in 0..8 {
+                for pixel in 0..8 {
+                    let shade = 3 - framebuffer[(row * 8 + line) * SCREEN_WIDTH + column * 8 + pixel];
+                    bytes[line * 2] |= (shade & 0x01) << (7 - pixel);
+                    bytes[line * 2 + 1] |= (shade >> 1 & 0x01) << (7 - pixel);
+                }
+            }
+        }
+        data
+    }
+
+    /// Ends a frame of the game screen, given as the grey shades BGP and OBP turned it into like
+    /// `Ppu::framebuffer`. Finishes a transfer the game asked for from it, then shows the frame
+    /// inside the border.
+    pub fn end_frame(&mut self, framebuffer: &[u8], output: &mut Box<dyn Output>) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = Sgb::transfer_data(framebuffer);
+            match transfer {
+                Transfer::Palettes => {
+                    for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                        *color = color_at(&data, i * 2);
+                    }
+                }
+                Transfer::Attributes => self.attribute_files.copy_from_slice(&data[..ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]),
+                Transfer::BorderTiles(upper) => {
+                    let offset = upper as usize * TRANSFER_SIZE;
+                    self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::BorderMap => {
+                    for (i, entry) in self.border_map.iter_mut().enumerate() {
+                        *entry = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+                    }
+                    for (i, color) in self.border_palettes.iter_mut().flatten().enumerate() {
+                        *color = color_at(&data, 0x800 + i * 2);
+                    }
+                }
+            }
+        }
+        if self.mask == 0 {
+            for (i, color) in self.screen.iter_mut().enumerate() {
+                let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                let palette = self.attributes[y / 8 * CELLS_X + x / 8] as usize;
+                *color = self.palettes[palette][(3 - framebuffer[i].min(3)) as usize];
+            }
+        }
+        self.render(output);
+    }
+
+    /// Draws the border with the game screen as last shown inside it.
+    pub fn render(&self, output: &mut Box<dyn Output>) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..BORDER_HEIGHT {
+            for x in 0..BORDER_WIDTH {
+                let (screen_x, screen_y) = (x.wrapping_sub(SCREEN_X), y.wrapping_sub(SCREEN_Y));
+                let color = if screen_x < SCREEN_WIDTH && screen_y < SCREEN_HEIGHT {
+                    match self.mask {
+                        2 => 0x0000,
+                        3 => backdrop,
+                        _ => self.screen[screen_y * SCREEN_WIDTH + screen_x],
+                    }
+                } else {
+                    self.border_pixel(x, y).unwrap_or(backdrop)
+                };
+                output.write_color(x as u16, y as u16, color);
+            }
+        }
+    }
+
+    /// Colour of the border at a pixel, `None` where it is transparent. Tiles are 4bpp in the
+    /// SNES layout, with planes 2 and 3 in the second half.
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[y / 8 * BORDER_MAP_WIDTH + x / 8];
+        let tile = &self.border_tiles[(entry & 0xFF) as usize * BORDER_TILE_SIZE..];
+        let column = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let color = [tile[row * 2], tile[row * 2 + 1], tile[16 + row * 2], tile[16 + row * 2 + 1]].iter().enumerate()
+            .fold(0, |color, (plane, byte)| color | (byte >> (7 - column) & 0x01) << plane);
+        (color != 0).then(|| self.border_palettes[(entry >> 10 & 0x03) as usize][color as usize])
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.packet);
+        state.u16(self.bits.map_or(u16::MAX, |bits| bits as u16));
+        state.bytes(&self.command);
+        state.u8(self.lines);
+        state.u8(self.players);
+        state.u8(self.player);
+        for color in self.palettes.iter().flatten().chain(self.system_palettes.iter()) {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.bytes(&self.attribute_files);
+        state.u8(self.mask);
+        state.u8(Transfer::to_u8(self.transfer));
+        state.bytes(&self.border_tiles);
+        for color in self.border_map.iter().chain(self.border_palettes.iter().flatten()).chain(self.screen.iter()) {
+            state.u16(*color);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.packet)?;
+        self.bits = match state.u16()? {
+            u16::MAX => None,
+            bits if (bits as usize) < PACKET_SIZE * 8 => Some(bits as usize),
+            _ => return Err(StateError::Corrupt),
+        };
+        self.command = state.vec()?;
+        self.lines = state.u8()?;
+        self.players = state.u8()?;
+        self.player = state.u8()?;
+        if !matches!(self.players, 1 | 2 | 4) || self.player >= self.players || self.command.len() >= 7 * PACKET_SIZE {
+            return Err(StateError::Corrupt);
+        }
+        for color in self.palettes.iter_mut().flatten().chain(self.system_palettes.iter_mut()) {
+            *color = state.u16()?;
+        }
+        state.bytes_into(&mut self.attributes)?;
+        if self.attributes.iter().any(|palette| *palette > 3) {
+            return Err(StateError::Corrupt);
+        }
+        state.bytes_into(&mut self.attribute_files)?;
+        self.mask = state.u8()? & 0x03;
+        self.transfer = Transfer::from_u8(state.u8()?)?;
+        state.bytes_into(&mut self.border_tiles)?;
+        for color in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()).chain(self.screen.iter_mut()) {
+            *color = state.u16()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sgb::{Sgb, CELLS_X, PACKET_SIZE};
+
+    fn send(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for bit in 0..PACKET_SIZE * 8 {
+            sgb.write_p1(if packet[bit / 8] >> (bit % 8) & 0x01 != 0 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    #[test]
+    fn palettes_and_attributes() {
+        let mut sgb = Sgb::new();
+        // PAL01 with a red colour 1 in palette 1
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = 0x01;
+        packet[1..3].copy_from_slice(&0x7FFFu16.to_le_bytes());
+        packet[9..11].copy_from_slice(&0x001Fu16.to_le_bytes());
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0x001F, 0x0000, 0x0000]);
+        assert_eq!(sgb.palettes[3][0], 0x7FFF);
+
+        // ATTR_BLK with palette 1 inside (1, 1)-(4, 3) and its border, 2 outside
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = 0x04 << 3 | 1;
+        packet[1] = 1;
+        packet[2..8].copy_from_slice(&[0x07, 0x01 | 0x01 << 2 | 0x02 << 4, 1, 1, 4, 3]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.attributes[CELLS_X + 1], 1);
+        assert_eq!(sgb.attributes[2 * CELLS_X + 2], 1);
+        assert_eq!(sgb.attributes[0], 2);
+        assert_eq!(sgb.attributes[5 * CELLS_X + 5], 2);
+
+        // ATTR_DIV splitting the screen at row 9
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = 0x06 << 3 | 1;
+        packet[1..3].copy_from_slice(&[0x40 | 0x02 << 4 | 0x01 << 2 | 0x03, 9]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.attributes[8 * CELLS_X], 1);
+        assert_eq!(sgb.attributes[9 * CELLS_X], 2);
+        assert_eq!(sgb.attributes[17 * CELLS_X + 19], 3);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.joypad_id(), 0x0F);
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 0x01;
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.joypad_id(), 0x0F);
+        // A normal read of both button groups ends with P15 going high
+        for lines in [0x20, 0x30, 0x10, 0x30] {
+            sgb.write_p1(lines);
+        }
+        assert_eq!(sgb.joypad_id(), 0x0E);
+        for lines in [0x10, 0x30] {
+            sgb.write_p1(lines);
+        }
+        assert_eq!(sgb.player(), 0);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+pub const STATE_MAGIC: &[u8; 4] = b"RGBS";
+/// Bump whenever anything written by a `save_state` changes, old states are rejected on load.
+pub const STATE_VERSION: u32 = 9;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    NotAState,
+    IncompatibleVersion { found: u32, expected: u32 },
+    RomMismatch { expected: String, found: String },
+    Corrupt,
+    /// A BESS state of a console family this emulator does not emulate, by its model string.
+    UnsupportedModel(String),
+    /// Slots are stored next to the ROM, which a machine loaded from bytes does not have.
+    NoRomPath,
+    /// The state was saved while the boot ROM ran, and no boot ROM is loaded.
+    NoBootRom,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::Io(error) => write!(f, "could not access save state: {}", error),
+            StateError::NotAState => write!(f, "not a rusty-gb save state"),
+            StateError::IncompatibleVersion { found, expected } => write!(f, "save state version {} is not supported, expected {}", found, expected),
+            StateError::RomMismatch { expected, found } => write!(f, "save state is for \"{}\" but \"{}\" is loaded", found, expected),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+            StateError::UnsupportedModel(model) => write!(f, "save state is for an unsupported model \"{}\"", model.trim_end()),
+            StateError::NoRomPath => write!(f, "no ROM path to store save state slots next to"),
+            StateError::NoBootRom => write!(f, "save state was made during the boot ROM, which is not loaded"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(error: io::Error) -> Self {
+        StateError::Io(error)
+    }
+}
+
+/// Little endian encoder for save states.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: vec![] }
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+    /// Length prefixed bytes.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Decoder for what `StateWriter` wrote, any read past the end is reported as a corrupt state.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(len).ok_or(StateError::Corrupt)?;
+        let slice = self.data.get(self.position..end).ok_or(StateError::Corrupt)?;
+        self.position = end;
+        Ok(slice)
+    }
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn i16(&mut self) -> Result<i16, StateError> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn usize(&mut self) -> Result<usize, StateError> {
+        usize::try_from(self.u64()?).map_err(|_| StateError::Corrupt)
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        Ok(self.bytes()?.to_vec())
+    }
+    /// Reads bytes into a buffer of a fixed size, like a memory bank.
+    pub fn bytes_into(&mut self, target: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != target.len() {
+            return Err(StateError::Corrupt);
+        }
+        target.copy_from_slice(bytes);
+        Ok(())
+    }
+    pub fn finish(&self) -> Result<(), StateError> {
+        match self.position == self.data.len() {
+            true => Ok(()),
+            false => Err(StateError::Corrupt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{StateError, StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u64(u64::MAX);
+        writer.i16(-3);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_inner();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 0x12);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x3456);
+        assert_eq!(reader.u64().unwrap(), u64::MAX);
+        assert_eq!(reader.i16().unwrap(), -3);
+        let mut buffer = [0; 3];
+        reader.bytes_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        reader.finish().unwrap();
+        assert!(matches!(reader.u8(), Err(StateError::Corrupt)));
+    }
+}
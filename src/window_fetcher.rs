@@ -1,9 +1,12 @@
 use crate::bus::{Bus, VRAM};
+use crate::fetcher::{tile_attributes, tile_row};
 use crate::ppu::OAM;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::window_fetcher::WindowFetcherState::{
     PushToFIFO, ReadTileData0, ReadTileData1, ReadTileID,
 };
 
+#[derive(Clone)]
 enum WindowFetcherState {
     ReadTileData0,
     ReadTileData1,
@@ -11,10 +14,12 @@ enum WindowFetcherState {
     ReadTileID,
 }
 
+#[derive(Clone)]
 pub struct WindowFetcher {
     ticks: usize,
     tile_index: u8,
     tile_id: u8,
+    attributes: u8,
     map_address: u16,
     tile_line: u8,
     line_index: u8,
@@ -33,6 +38,7 @@ impl WindowFetcher {
             map_address: 0,
             tile_line: 0,
             tile_id: 0,
+            attributes: 0,
             pixel_data: [0; 16],
             oams: vec![],
             fifo_bg: vec![],
@@ -41,6 +47,42 @@ impl WindowFetcher {
             line_index: 0,
         }
     }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.usize(self.ticks);
+        state.u8(self.tile_index);
+        state.u8(self.tile_id);
+        state.u8(self.attributes);
+        state.u16(self.map_address);
+        state.u8(self.tile_line);
+        state.u8(self.line_index);
+        state.bytes(&self.pixel_data);
+        state.bytes(&self.fifo_bg);
+        state.u8(match self.state {
+            WindowFetcherState::ReadTileData0 => 0,
+            WindowFetcherState::ReadTileData1 => 1,
+            WindowFetcherState::PushToFIFO => 2,
+            WindowFetcherState::ReadTileID => 3,
+        });
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ticks = state.usize()?;
+        self.tile_index = state.u8()?;
+        self.tile_id = state.u8()?;
+        self.attributes = state.u8()?;
+        self.map_address = state.u16()?;
+        self.tile_line = state.u8()?;
+        self.line_index = state.u8()?;
+        state.bytes_into(&mut self.pixel_data)?;
+        self.fifo_bg = state.vec()?;
+        self.state = match state.u8()? {
+            0 => WindowFetcherState::ReadTileData0,
+            1 => WindowFetcherState::ReadTileData1,
+            2 => WindowFetcherState::PushToFIFO,
+            3 => WindowFetcherState::ReadTileID,
+            _ => return Err(StateError::Corrupt),
+        };
+        Ok(())
+    }
     pub fn tick(&mut self, bus: &mut Bus) {
         match self.state {
             ReadTileData0 => self.read_tile_data(bus),
@@ -50,24 +92,7 @@ impl WindowFetcher {
     }
 
     fn read_tile_data(&mut self, bus: &Bus) {
-        let offset = match bus.get_ldlc_bg_window_tiles() {
-            true => 0x8000 + self.tile_id as u16 * 16,
-            false => {
-                if self.tile_id <= 127 {
-                    0x9000 + self.tile_id as u16 * 16
-                } else {
-                    0x8000 + self.tile_id as u16 * 16
-                }
-            }
-        };
-
-        let address = offset + self.tile_line as u16 * 2;
-        let value1 = bus._get(address);
-        let value2 = bus._get(address + 1);
-        for bit in 0..=7 {
-            self.pixel_data[7 - bit] = (value1 >> bit) & 1;
-            self.pixel_data[7 - bit] |= ((value2 >> bit) & 1 ) << 1;
-        }
+        self.pixel_data[..8].copy_from_slice(&tile_row(bus, self.tile_id, self.attributes, self.tile_line));
 
         self.state = PushToFIFO;
     }
@@ -82,7 +107,9 @@ impl WindowFetcher {
         }
     }
     fn read_tile_id(&mut self, bus: &Bus) {
-        self.tile_id = bus.get(self.map_address + self.tile_index as u16);
+        let address = self.map_address + self.tile_index as u16;
+        self.tile_id = bus.vram(0, address);
+        self.attributes = tile_attributes(bus, address);
         self.pixel_data.fill(0);
         self.state = ReadTileData0
     }